@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+
+use crate::features::{AnalysisConfig, BasePitchConfig, F0Config};
+use crate::io::audio::{InputFormat, OutputFormat};
+use crate::io::scx::CacheConfig;
+use crate::stretch::StretchConfig;
+
+pub const CONFIG_FILE: &str = "straycrab.toml";
+pub const DEFAULT_FRAME_PERIOD: f64 = 5.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Named flag combinations, referenced in the flags string as `@name`
+    pub presets: BTreeMap<String, String>,
+    /// Analysis and synthesis frame period in whole milliseconds (1-10)
+    pub frame_period: f64,
+    /// How the sustained region is extended to the requested length
+    pub stretch: StretchConfig,
+    /// How samples are read for analysis
+    pub input: InputFormat,
+    /// Format of the rendered files
+    pub output: OutputFormat,
+    /// How feature files are stored
+    pub cache: CacheConfig,
+    /// Where F0 comes from during analysis
+    pub f0: F0Config,
+    /// Spectral analysis settings
+    pub analysis: AnalysisConfig,
+    /// Where base pitch comes from besides the analyzed F0
+    pub base_pitch: BasePitchConfig,
+}
+
+/// Sections a voicebank can override with a `straycrab.toml` of its own
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct VoicebankConfig {
+    f0: Option<F0Config>,
+    base_pitch: Option<BasePitchConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            presets: BTreeMap::new(),
+            frame_period: DEFAULT_FRAME_PERIOD,
+            stretch: StretchConfig::default(),
+            input: InputFormat::default(),
+            output: OutputFormat::default(),
+            cache: CacheConfig::default(),
+            f0: F0Config::default(),
+            analysis: AnalysisConfig::default(),
+            base_pitch: BasePitchConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        match Self::locate() {
+            Some(path) if path.exists() => Self::from_file(&path),
+            _ => Ok(Config::default()),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let data = std::fs::read_to_string(path)?;
+        Self::parse(&data)
+    }
+
+    pub fn parse(data: &str) -> Result<Config> {
+        let config: Config = toml::from_str(data)?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// This config with the overrides of the closest `straycrab.toml` in `path`'s folder or above, so a
+    /// voicebank can pick its own analysis settings
+    pub fn for_path(&self, path: &Path) -> Result<Config> {
+        let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let Some(file) = path.ancestors().map(|dir| dir.join(CONFIG_FILE)).find(|file| file.is_file()) else {
+            return Ok(self.clone());
+        };
+
+        let overrides: VoicebankConfig = toml::from_str(&std::fs::read_to_string(&file)?)
+            .map_err(|e| anyhow!("Failed to read {:?}: {}", file, e))?;
+        let mut config = self.clone();
+        if let Some(f0) = overrides.f0 {
+            f0.validate()?;
+            config.f0 = f0;
+        }
+        if let Some(base_pitch) = overrides.base_pitch {
+            base_pitch.validate()?;
+            config.base_pitch = base_pitch;
+        }
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        // WORLD synthesis works on whole-millisecond frame periods
+        if !(1.0..=10.0).contains(&self.frame_period) || self.frame_period.fract() != 0.0 {
+            bail!("frame_period must be a whole number of milliseconds between 1 and 10, got {}", self.frame_period);
+        }
+
+        if self.stretch.crossfade < 0.0 || self.stretch.segment <= 0.0 {
+            bail!("stretch crossfade must not be negative and segment must be positive");
+        }
+
+        self.input.validate()?;
+        self.output.validate()?;
+        self.cache.validate()?;
+        self.f0.validate()?;
+        self.analysis.validate()?;
+        self.base_pitch.validate()?;
+
+        Ok(())
+    }
+
+    fn locate() -> Option<PathBuf> {
+        // STRAYCRAB_CONFIG takes precedence, otherwise look next to the executable
+        if let Ok(path) = env::var("STRAYCRAB_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+
+        env::current_exe().ok()?.parent().map(|dir| dir.join(CONFIG_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::f0::PostProcess;
+    use crate::features::{F0Estimator, FrqUsage};
+    use crate::io::quant::Precision;
+    use crate::stretch::StretchMode;
+    use crate::util::testing::temp;
+
+    #[test]
+    fn test_parse_presets() {
+        let config = Config::parse(r#"
+            [presets]
+            soft = "B60g-5P70"
+            belt = "B0P100"
+        "#).unwrap();
+
+        assert_eq!(config.presets.len(), 2);
+        assert_eq!(config.presets["soft"], "B60g-5P70");
+    }
+
+    #[test]
+    fn test_empty_config() {
+        let config = Config::parse("").unwrap();
+
+        assert!(config.presets.is_empty());
+        assert_eq!(config.frame_period, DEFAULT_FRAME_PERIOD);
+    }
+
+    #[test]
+    fn test_stretch() {
+        let config = Config::parse(r#"
+            [stretch]
+            mode = "ping_pong"
+            crossfade = 40
+        "#).unwrap();
+
+        assert_eq!(config.stretch.mode, StretchMode::PingPong);
+        assert_eq!(config.stretch.crossfade, 40.0);
+        assert_eq!(config.stretch.segment, StretchConfig::default().segment);
+    }
+
+    #[test]
+    fn test_input() {
+        assert_eq!(Config::default().input.channel, None);
+        assert_eq!(Config::parse("[input]\nchannel = 1").unwrap().input.channel, Some(1));
+        assert_eq!(Config::parse("[input]\nsample_rate = 44100").unwrap().input.sample_rate, Some(44100));
+        assert!(Config::parse("[input]\nsample_rate = 100").is_err());
+    }
+
+    #[test]
+    fn test_output() {
+        let config = Config::parse(r#"
+            [output]
+            bit_depth = 16
+            sample_rate = 48000
+        "#).unwrap();
+
+        assert_eq!(config.output, OutputFormat { bit_depth: 16, sample_rate: 48000, dither: true });
+        assert!(Config::parse("[output]\nbit_depth = 20").is_err());
+    }
+
+    #[test]
+    fn test_cache() {
+        assert_eq!(Config::parse("").unwrap().cache.compression_level, 3);
+        assert_eq!(Config::parse("[cache]\ncompression_level = 19").unwrap().cache.compression_level, 19);
+
+        assert!(Config::parse("[cache]\ncompression_level = 0").is_err());
+        assert!(Config::parse("[cache]\ncompression_level = 23").is_err());
+
+        assert_eq!(Config::parse("").unwrap().cache.quantization, None);
+        assert_eq!(Config::parse("[cache]\nquantization = \"int8\"").unwrap().cache.quantization, Some(Precision::Int8));
+        assert!(Config::parse("[cache]\nquantization = \"int4\"").is_err());
+    }
+
+    #[test]
+    fn test_f0() {
+        assert_eq!(Config::parse("").unwrap().f0.frq, FrqUsage::Never);
+        assert_eq!(Config::parse("[f0]\nfrq = \"if_present\"").unwrap().f0.frq, FrqUsage::IfPresent);
+        assert!(Config::parse("[f0]\nfrq = \"sometimes\"").is_err());
+
+        let f0 = Config::parse("[f0]\nestimator = \"dio\"\noctave_correction = true\nmax_gap = 20\nmedian_window = 15.0").unwrap().f0;
+        assert_eq!(f0.estimator, F0Estimator::Dio);
+        assert_eq!(f0.postprocess, PostProcess { octave_correction: true, max_gap: 20.0, median_window: 15.0, ..PostProcess::default() });
+        assert!(Config::parse("[f0]\nmin_voiced = -5").is_err());
+    }
+
+    #[test]
+    fn test_analysis() {
+        let analysis = Config::parse("[analysis]\nquality = \"high\"\nmgc_dims = 96").unwrap().analysis;
+        assert_eq!((analysis.fft_size(44100), analysis.mgc_dims(), analysis.d4c_threshold()), (4096, 96, 0.25));
+
+        let analysis = Config::parse("[analysis]\nquality = \"draft\"\nfft_size = 4096").unwrap().analysis;
+        assert_eq!((analysis.fft_size(44100), analysis.mgc_dims()), (4096, 32));
+        assert_eq!(Config::default().analysis.fft_size(48000), 2048);
+
+        assert!(Config::parse("[analysis]\nfft_size = 3000").is_err());
+        assert!(Config::parse("[analysis]\nmgc_dims = 1024").is_err());
+        assert!(Config::parse("[analysis]\nd4c_threshold = 1.5").is_err());
+    }
+
+    #[test]
+    fn test_base_pitch() {
+        let pitch = Config::parse("[base_pitch]\nprefix_map = true\noverrides = { \"_あ\" = \"C4\", \"A3/_い\" = \"Bb3\" }").unwrap().base_pitch;
+        assert_eq!((pitch.folders, pitch.prefix_map, pitch.overrides.len()), (false, true, 2));
+        assert!(Config::parse("[base_pitch]\noverrides = { \"_あ\" = \"H4\" }").is_err());
+    }
+
+    #[test]
+    fn test_voicebank_overrides() {
+        let bank = temp("override_bank");
+        std::fs::create_dir_all(bank.join("C4")).unwrap();
+        std::fs::write(bank.join(CONFIG_FILE), "frame_period = 2.0\n[f0]\nestimator = \"dio\"\n[base_pitch]\nfolders = true").unwrap();
+
+        // Only [f0] and [base_pitch] are taken from the voicebank, for samples in any of its folders
+        let config = Config { frame_period: 3.0, ..Config::default() }.for_path(&bank.join("C4/a.wav")).unwrap();
+        assert_eq!((config.f0.estimator, config.frame_period), (F0Estimator::Dio, 3.0));
+        assert!(config.base_pitch.folders);
+
+        std::fs::write(bank.join(CONFIG_FILE), "[f0]\nmax_gap = -1").unwrap();
+        assert!(Config::default().for_path(&bank).is_err());
+        std::fs::remove_file(bank.join(CONFIG_FILE)).unwrap();
+    }
+
+    #[test]
+    fn test_frame_period() {
+        assert_eq!(Config::parse("frame_period = 2.0").unwrap().frame_period, 2.0);
+        assert_eq!(Config::parse("frame_period = 8").unwrap().frame_period, 8.0);
+
+        assert!(Config::parse("frame_period = 0.0").is_err());
+        assert!(Config::parse("frame_period = 12.0").is_err());
+        assert!(Config::parse("frame_period = 2.5").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Frames within this many octaves of a whole octave off their surroundings count as octave errors
+const OCTAVE_TOLERANCE: f64 = 0.25;
+/// Milliseconds on each side of a frame whose voiced frames it is compared against for octave errors
+const OCTAVE_SPAN: f64 = 100.0;
+
+/// Cleanup of an estimated F0 contour, where 0 marks unvoiced frames. Durations are in milliseconds and
+/// 0 disables a step, so the default leaves the contour untouched.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostProcess {
+    /// Voiced runs shorter than this are made unvoiced
+    pub min_voiced: f64,
+    /// Fold frames an octave above or below their surroundings back
+    pub octave_correction: bool,
+    /// Unvoiced gaps up to this long between voiced frames are filled in
+    pub max_gap: f64,
+    /// Width of the median filter run over each voiced run
+    pub median_window: f64,
+}
+
+impl PostProcess {
+    pub fn validate(&self) -> Result<()> {
+        for (name, ms) in [("min_voiced", self.min_voiced), ("max_gap", self.max_gap), ("median_window", self.median_window)] {
+            if !ms.is_finite() || ms < 0.0 {
+                bail!("f0 {} must be a non-negative number of milliseconds, got {}", name, ms);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Clean up `f0`, analyzed every `frame_period` ms, in place
+    pub fn apply(&self, f0: &mut [f64], frame_period: f64) {
+        let frames = |ms: f64| (ms / frame_period).round() as usize;
+
+        remove_blips(f0, frames(self.min_voiced));
+        if self.octave_correction {
+            correct_octaves(f0, frames(OCTAVE_SPAN));
+        }
+        fill_gaps(f0, frames(self.max_gap));
+        median_filter(f0, frames(self.median_window));
+    }
+}
+
+/// Unvoice runs of fewer than `min_len` voiced frames
+pub fn remove_blips(f0: &mut [f64], min_len: usize) {
+    for run in voiced_runs(f0) {
+        if run.len() < min_len {
+            f0[run].fill(0.0);
+        }
+    }
+}
+
+/// Move voiced frames lying a whole number of octaves off the median of the voiced frames within `span`
+/// frames back to that median's octave
+pub fn correct_octaves(f0: &mut [f64], span: usize) {
+    let source = f0.to_vec();
+
+    for (i, f) in f0.iter_mut().enumerate().filter(|(_, f)| **f > 0.0) {
+        let window = &source[i.saturating_sub(span)..(i + span + 1).min(source.len())];
+        let reference = median(window.iter().copied().filter(|f| *f > 0.0).collect());
+
+        let octaves = (*f / reference).log2();
+        let shift = octaves.round();
+        if shift != 0.0 && (octaves - shift).abs() < OCTAVE_TOLERANCE {
+            *f /= shift.exp2();
+        }
+    }
+}
+
+/// Interpolate unvoiced gaps of up to `max_len` frames between two voiced frames, on a log scale
+pub fn fill_gaps(f0: &mut [f64], max_len: usize) {
+    let runs = voiced_runs(f0);
+
+    for pair in runs.windows(2) {
+        let gap = pair[0].end..pair[1].start;
+        if gap.len() > max_len {
+            continue;
+        }
+
+        let (from, to) = (f0[gap.start - 1].ln(), f0[gap.end].ln());
+        let steps = (gap.len() + 1) as f64;
+        for (k, i) in gap.enumerate() {
+            f0[i] = (from + (to - from) * (k + 1) as f64 / steps).exp();
+        }
+    }
+}
+
+/// Median filter `width` frames wide over each voiced run, leaving voicing as is
+pub fn median_filter(f0: &mut [f64], width: usize) {
+    if width < 2 {
+        return;
+    }
+    let half = width / 2;
+
+    for run in voiced_runs(f0) {
+        let source = f0[run.clone()].to_vec();
+        for (k, f) in f0[run].iter_mut().enumerate() {
+            *f = median(source[k.saturating_sub(half)..(k + half + 1).min(source.len())].to_vec());
+        }
+    }
+}
+
+/// Frame ranges of consecutive voiced frames
+fn voiced_runs(f0: &[f64]) -> Vec<Range<usize>> {
+    let mut runs = vec![];
+    let mut start = None;
+
+    for (i, f) in f0.iter().enumerate() {
+        match (*f > 0.0, start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                runs.push(s..i);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        runs.push(s..f0.len());
+    }
+
+    runs
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let n = values.len();
+
+    match n % 2 {
+        1 => values[n / 2],
+        _ => (values[n / 2 - 1] + values[n / 2]) / 2.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_blips() {
+        let mut f0 = vec![0.0, 200.0, 0.0, 0.0, 210.0, 211.0, 212.0, 0.0, 300.0, 301.0];
+        remove_blips(&mut f0, 3);
+        assert_eq!(f0, [0.0, 0.0, 0.0, 0.0, 210.0, 211.0, 212.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_correct_octaves() {
+        let mut f0 = vec![220.0; 20];
+        f0[5] = 441.0;
+        f0[6] = 109.0;
+        // A fifth up is a real note change
+        f0[12] = 330.0;
+        f0[15] = 0.0;
+        correct_octaves(&mut f0, 5);
+
+        assert!((f0[5] - 220.5).abs() < 1e-9 && (f0[6] - 218.0).abs() < 1e-9);
+        assert_eq!((f0[12], f0[15]), (330.0, 0.0));
+    }
+
+    #[test]
+    fn test_fill_gaps() {
+        let mut f0 = vec![0.0, 100.0, 0.0, 400.0, 0.0, 0.0, 0.0, 500.0, 0.0];
+        fill_gaps(&mut f0, 2);
+
+        // Geometric midpoint of 100 and 400; the longer gap and the edges stay unvoiced
+        assert!((f0[2] - 200.0).abs() < 1e-9);
+        assert_eq!(f0[4..7], [0.0; 3]);
+        assert_eq!((f0[0], f0[8]), (0.0, 0.0));
+    }
+
+    #[test]
+    fn test_median_filter() {
+        let mut f0 = vec![0.0, 200.0, 200.0, 260.0, 200.0, 200.0, 0.0, 150.0];
+        median_filter(&mut f0, 3);
+        assert_eq!(f0, [0.0, 200.0, 200.0, 200.0, 200.0, 200.0, 0.0, 150.0]);
+    }
+
+    #[test]
+    fn test_apply() {
+        // 5 ms frames: a 10 ms blip, an octave error and a 15 ms dropout
+        let mut f0 = vec![0.0, 0.0, 180.0, 180.0, 0.0, 0.0];
+        f0.extend([220.0; 10]);
+        f0.extend([440.0, 0.0, 0.0, 0.0]);
+        f0.extend([220.0; 10]);
+
+        let mut cleaned = f0.clone();
+        PostProcess::default().apply(&mut cleaned, 5.0);
+        assert_eq!(cleaned, f0);
+
+        let process = PostProcess { min_voiced: 15.0, octave_correction: true, max_gap: 20.0, median_window: 15.0 };
+        process.apply(&mut cleaned, 5.0);
+        assert_eq!(cleaned[..6], [0.0; 6]);
+        assert!(cleaned[6..].iter().all(|f| (f - 220.0).abs() < 1e-9), "{:?}", cleaned);
+
+        assert!(PostProcess { max_gap: -1.0, ..PostProcess::default() }.validate().is_err());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod resample;
+pub mod f0;
\ No newline at end of file
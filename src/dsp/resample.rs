@@ -0,0 +1,191 @@
+use std::f64::consts::PI;
+
+/// Zero crossings of the interpolation kernel on each side
+const ZERO_CROSSINGS: usize = 64;
+/// Kernel table entries per zero crossing
+const OVERSAMPLING: usize = 512;
+/// Kaiser window shape, giving roughly 96 dB of stopband attenuation
+const KAISER_BETA: f64 = 9.6;
+/// -6 dB point of the anti-aliasing filter, relative to the lower Nyquist frequency
+const ROLLOFF: f64 = 0.92;
+
+/// Band-limited sample rate converter for arbitrary ratios, using a tabulated Kaiser-windowed sinc kernel.
+/// The table holds `OVERSAMPLING` phases per input sample, and samples between phases are interpolated.
+pub struct SincResampler {
+    from: u32,
+    to: u32,
+    /// Kernel bandwidth relative to the input rate; below 1 when downsampling
+    cutoff: f64,
+    table: Vec<f64>,
+}
+
+impl SincResampler {
+    pub fn new(from: u32, to: u32) -> SincResampler {
+        let cutoff = ROLLOFF * (to as f64 / from as f64).min(1.0);
+
+        let n = ZERO_CROSSINGS * OVERSAMPLING;
+        let table = (0..=n + 1).map(|i| {
+            let u = i as f64 / OVERSAMPLING as f64;
+            sinc(u) * kaiser(u / ZERO_CROSSINGS as f64, KAISER_BETA)
+        }).collect();
+
+        SincResampler { from, to, cutoff, table }
+    }
+
+    pub fn process(&self, x: &[f32]) -> Vec<f32> {
+        if self.from == self.to || x.is_empty() {
+            return x.to_vec();
+        }
+
+        let n = (x.len() as f64 * self.to as f64 / self.from as f64).round() as usize;
+        let step = self.from as f64 / self.to as f64;
+        let reach = ZERO_CROSSINGS as f64 / self.cutoff;
+
+        (0..n).map(|i| {
+            let t = i as f64 * step;
+            let first = (t - reach).ceil().max(0.0) as usize;
+            let last = ((t + reach).floor() as usize).min(x.len() - 1);
+
+            let mut acc = 0.0;
+            for (k, sample) in x.iter().enumerate().take(last + 1).skip(first) {
+                acc += *sample as f64 * self.kernel((t - k as f64).abs() * self.cutoff);
+            }
+
+            (acc * self.cutoff) as f32
+        }).collect()
+    }
+
+    /// Windowed sinc at `u` zero crossings from the centre
+    fn kernel(&self, u: f64) -> f64 {
+        let pos = u * OVERSAMPLING as f64;
+        let i = pos as usize;
+        if i >= ZERO_CROSSINGS * OVERSAMPLING {
+            return 0.0;
+        }
+
+        let frac = pos - i as f64;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * frac
+    }
+}
+
+/// Convert `x` from `from` Hz to `to` Hz
+pub fn resample(x: &[f32], from: u32, to: u32) -> Vec<f32> {
+    SincResampler::new(from, to).process(x)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window at `x` in [-1, 1]
+fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+
+    bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+/// Zeroth order modified Bessel function of the first kind
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+
+    for k in 1..50 {
+        term *= (half / k as f64) * (half / k as f64);
+        sum += term;
+        if term < sum * 1e-16 {
+            break;
+        }
+    }
+
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, fs: u32, seconds: f64) -> Vec<f32> {
+        (0..(fs as f64 * seconds) as usize).map(|i| (2.0 * PI * freq * i as f64 / fs as f64).sin() as f32 * 0.5).collect()
+    }
+
+    /// Amplitude of the `freq` component in the middle of `y`, by least squares against sin and cos
+    fn amplitude(y: &[f32], freq: f64, fs: u32) -> f64 {
+        let (start, end) = (y.len() / 4, 3 * y.len() / 4);
+        let (mut ss, mut cc, mut ys, mut yc) = (0.0, 0.0, 0.0, 0.0);
+
+        for (i, v) in y.iter().enumerate().take(end).skip(start) {
+            let phase = 2.0 * PI * freq * i as f64 / fs as f64;
+            let (s, c) = (phase.sin(), phase.cos());
+            ss += s * s;
+            cc += c * c;
+            ys += *v as f64 * s;
+            yc += *v as f64 * c;
+        }
+
+        ((ys / ss).powi(2) + (yc / cc).powi(2)).sqrt()
+    }
+
+    fn rms(y: &[f32]) -> f64 {
+        let y = &y[y.len() / 4..3 * y.len() / 4];
+        (y.iter().map(|v| (*v as f64).powi(2)).sum::<f64>() / y.len() as f64).sqrt()
+    }
+
+    fn db(x: f64) -> f64 {
+        20.0 * x.log10()
+    }
+
+    #[test]
+    fn test_lengths() {
+        let x = vec![0.0; 44100];
+
+        assert_eq!(resample(&x, 44100, 48000).len(), 48000);
+        assert_eq!(resample(&x, 44100, 22050).len(), 22050);
+        assert_eq!(resample(&x, 44100, 96000).len(), 96000);
+        assert_eq!(resample(&x, 44100, 44100), x);
+    }
+
+    #[test]
+    fn test_passband_ripple() {
+        // Up to 80 % of the lower Nyquist frequency the level stays within 0.01 dB
+        for (from, to) in [(22050, 44100), (48000, 44100), (96000, 44100), (44100, 48000)] {
+            let nyquist = from.min(to) as f64 / 2.0;
+
+            for fraction in [0.05, 0.2, 0.4, 0.6, 0.8] {
+                let freq = nyquist * fraction;
+                let y = resample(&sine(freq, from, 0.25), from, to);
+                let gain = db(amplitude(&y, freq, to) / 0.5);
+
+                assert!(gain.abs() < 0.01, "{} -> {} Hz at {} Hz: {} dB", from, to, freq, gain);
+            }
+        }
+    }
+
+    #[test]
+    fn test_stopband_attenuation() {
+        // Tones above the output Nyquist frequency must not alias back into the output
+        for (from, to, freq) in [(96000, 44100, 23500.0), (96000, 44100, 30000.0), (48000, 22050, 12000.0), (48000, 44100, 23000.0)] {
+            let y = resample(&sine(freq, from, 0.25), from, to);
+            let attenuation = db(rms(&y) / (0.5 / 2f64.sqrt()));
+
+            assert!(attenuation < -90.0, "{} -> {} Hz at {} Hz: {} dB", from, to, freq, attenuation);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let x = sine(1000.0, 44100, 0.1);
+        let y = resample(&resample(&x, 44100, 48000), 48000, 44100);
+
+        assert_eq!(y.len(), x.len());
+        for (a, b) in x.iter().zip(y.iter()).skip(500).take(3000) {
+            assert!((a - b).abs() < 1e-3);
+        }
+    }
+}
\ No newline at end of file
@@ -1,144 +1,967 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
 
+use crate::config::Config;
+use crate::dsp::f0::PostProcess;
+use crate::dsp::resample::resample;
 use crate::io::audio::read_wav;
-use crate::io::bin::{decode, encode};
-use crate::util::misc::{base_frq, get_fft_size, DEFAULT_FS, F0_CEIL, F0_FLOOR};
+use crate::io::bin::deserialize;
+use crate::io::npz;
+use crate::io::quant::QuantizedFeatures;
+use crate::io::frq::Frq;
+use crate::io::prefix_map::PrefixMap;
+use crate::io::scx::{self, AnalysisParams, Archive, BaseSource, CacheConfig, Compression, F0Source, Header, Kind, SourceInfo};
+use crate::util::misc::{base_frq, fft_size_for, mtof, parse_note, F0_CEIL, F0_FLOOR};
 
-use rsworld_sys::{HarvestOption, CheapTrickOption, D4COption};
+use rsworld_sys::{HarvestOption, DioOption, CheapTrickOption, D4COption};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// D4C threshold of every preset; WORLD's own 0.85 unvoices too much of breathy samples
+const D4C_THRESHOLD: f64 = 0.25;
+
+/// Features of a whole voicebank, keyed by path relative to the voicebank root with `/` separators.
+/// Databases read from disk stay memory-mapped, and each entry is only decompressed when it is requested.
 pub struct FeatureDatabase {
-    pub features: HashMap<String, Features>
+    archive: Option<Archive>,
+    /// Entries added since loading, which take precedence over archived ones
+    added: HashMap<String, DatabaseEntry>,
+    /// Archived entries removed since loading
+    removed: HashSet<String>,
+}
+
+/// A sample in a voicebank database. Entries built from bare features record neither their analysis
+/// parameters nor their source, so they are always reanalyzed by the resampler.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DatabaseEntry {
+    pub analysis: Option<AnalysisParams>,
+    pub source: Option<SourceInfo>,
+    pub features: Features,
+}
+
+impl DatabaseEntry {
+    pub fn analyze(file: &Path, config: &Config) -> Result<DatabaseEntry> {
+        let source = SourceInfo::of(file)?;
+        let (features, analysis) = Features::analyze(file, config)?;
+
+        Ok(DatabaseEntry { analysis: Some(analysis), source: Some(source), features })
+    }
+}
+
+/// How database entries are stored, with the features possibly quantized
+#[derive(Serialize, Deserialize)]
+struct StoredEntry<'a> {
+    analysis: Option<AnalysisParams>,
+    source: Option<SourceInfo>,
+    features: StoredFeatures<'a>,
+}
+
+impl<'a> StoredEntry<'a> {
+    fn new(entry: &'a DatabaseEntry, cache: &CacheConfig) -> StoredEntry<'a> {
+        StoredEntry { analysis: entry.analysis.clone(), source: entry.source.clone(), features: StoredFeatures::new(&entry.features, cache) }
+    }
+}
+
+impl From<StoredEntry<'_>> for DatabaseEntry {
+    fn from(stored: StoredEntry) -> DatabaseEntry {
+        DatabaseEntry { analysis: stored.analysis, source: stored.source, features: stored.features.into_features() }
+    }
+}
+
+impl From<Features> for DatabaseEntry {
+    fn from(features: Features) -> DatabaseEntry {
+        DatabaseEntry { analysis: None, source: None, features }
+    }
 }
 
 impl FeatureDatabase {
     pub fn new(features: HashMap<String, Features>) -> FeatureDatabase {
         FeatureDatabase {
-            features
+            archive: None,
+            added: features.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            removed: HashSet::new(),
         }
     }
 
-    pub fn generate(file: &PathBuf) -> Result<Self> {
-        // Generate features for each .wav file in the directory
-        let mut lib = HashMap::new();
+    /// Open the database of the voicebank `file` belongs to: `<dir>/<dir>.scx` in the nearest enclosing
+    /// directory that has one. Returns the database and its path.
+    pub fn find(file: &Path) -> Option<(FeatureDatabase, PathBuf)> {
+        file.ancestors().skip(1).find_map(|dir| {
+            let path = dir.join(format!("{}.scx", dir.file_name()?.to_str()?));
+            if !path.is_file() {
+                return None;
+            }
 
-        for entry in tqdm::tqdm(std::fs::read_dir(file)?) {
-            let entry = entry?;
-            let path = entry.path();
+            // A sample named after its folder has a per-file cache at the same path
+            match Self::from_file(&path) {
+                Ok(db) => Some((db, path)),
+                Err(e) => {
+                    log::debug!("Not using {:?} as voicebank database: {}", path, e);
+                    None
+                }
+            }
+        })
+    }
+
+    /// The database of the voicebank at `dir`
+    pub fn open(dir: &Path) -> Result<FeatureDatabase> {
+        let path = Self::path_in(dir)?;
+        if !path.is_file() {
+            bail!("{:?} has no feature database; run `straycrab analyze` first", dir);
+        }
 
-            if path.is_file() && path.to_str().unwrap().ends_with(".wav") {
-                let features = Features::generate(&path)?;
-                lib.insert(path.file_name().unwrap().to_str().unwrap().to_string(), features);
+        Self::from_file(&path)
+    }
+
+    /// Key of `file` in the database of the voicebank at `root`
+    pub fn key(root: &Path, file: &Path) -> Option<String> {
+        let relative = file.strip_prefix(root).ok()?;
+        let parts = relative.components().map(|c| c.as_os_str().to_str()).collect::<Option<Vec<&str>>>()?;
+
+        Some(parts.join("/"))
+    }
+
+    /// Analyze every `.wav` under `dir`, in subfolders too, across all cores, and write the database to
+    /// `<dir>/<dir>.scx`. Samples whose entry in an existing database is still fresh are kept as they are,
+    /// fresh per-file caches left by rendering are taken over and removed, and entries of samples that no
+    /// longer exist are dropped. A sample that fails to analyze is reported and left out rather than failing
+    /// the whole voicebank.
+    pub fn generate(dir: &Path, config: &Config, report: &(dyn Fn(&Progress) + Sync)) -> Result<Self> {
+        let db_path = Self::path_in(dir)?;
+        let mut db = Self::open_or_new(&db_path);
+
+        let mut files = vec![];
+        find_samples(dir, &mut files)?;
+        files.sort();
+        let files = files.into_iter().filter_map(|file| Some((Self::key(dir, &file)?, file))).collect::<Vec<(String, PathBuf)>>();
+
+        let total = files.len();
+        let done = AtomicUsize::new(0);
+        report(&Progress::Start { total });
+
+        let results = files.par_iter().map(|(key, file)| {
+            // Per-file caches are newer than the database entry, like when rendering
+            let own_cache = file.with_extension("scx") != db_path;
+            let result = match own_cache.then(|| Features::cached_entry(file, config)).transpose() {
+                Ok(Some(Some(entry))) => Ok((SampleStatus::Analyzed, Some(entry))),
+                Ok(_) => match db.fresh_entry(key, file, config) {
+                    // Touched samples are rewritten only to record their new modification time, so renders
+                    // don't hash them again
+                    Ok(Some((entry, touched))) => Ok((SampleStatus::Skipped, touched.then_some(entry))),
+                    Ok(None) => DatabaseEntry::analyze(file, config).map(|entry| (SampleStatus::Analyzed, Some(entry))),
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+
+            let status = result.as_ref().map_or(SampleStatus::Failed, |(status, _)| *status);
+            report(&Progress::Sample {
+                key: key.clone(),
+                status,
+                done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                total,
+                error: result.as_ref().err().map(|e| e.to_string()),
+            });
+
+            result
+        }).collect::<Vec<Result<(SampleStatus, Option<DatabaseEntry>)>>>();
+
+        let (mut analyzed, mut skipped, mut failed) = (0, 0, 0);
+        let present = files.iter().map(|(key, _)| key.as_str()).collect::<HashSet<&str>>();
+        for key in db.keys().into_iter().filter(|k| !present.contains(k)).map(String::from).collect::<Vec<String>>() {
+            db.remove(&key);
+        }
+        let mut stored = vec![];
+        for ((key, file), result) in files.iter().zip(results) {
+            match result {
+                Ok((status, entry)) => {
+                    if let Some(entry) = entry {
+                        db.insert(key.clone(), entry);
+                    }
+                    match status {
+                        SampleStatus::Analyzed => analyzed += 1,
+                        _ => skipped += 1,
+                    }
+                    stored.push(file);
+                }
+                Err(_) => {
+                    db.remove(key);
+                    failed += 1;
+                }
             }
         }
 
-        let res = Self::new(lib);
+        db.to_file(&db_path, &config.cache)?;
+        let db = Self::from_file(&db_path)?;
 
-        let path = file.file_stem().unwrap().to_str().unwrap();
-        res.to_file(&file.join(format!("{}.scx", path)))?;
+        // Per-file caches of samples in the database are either in it now or stale
+        for cache in stored.into_iter().map(|file| file.with_extension("scx")).filter(|c| *c != db_path && c.is_file()) {
+            if let Err(e) = std::fs::remove_file(&cache) {
+                log::warn!("Could not remove {:?}: {}", cache, e);
+            }
+        }
+        report(&Progress::Finish { analyzed, skipped, failed });
 
-        Ok(res)
+        Ok(db)
     }
 
-    pub fn to_file(&self, path: &PathBuf) -> Result<()> {
-        // let mut file = File::create(path)?;
-        let serialized = bincode::serialize(&self)?;
-        // file.write_all(&serialized)?;
-        encode(&serialized, path)?;
+    /// Add straycat's `.sc.npz` caches of the samples under `dir` to the database at `<dir>/<dir>.scx`, as if
+    /// they had been analyzed with the current settings. straycat always analyzes at 44.1 kHz with a 5 ms frame
+    /// period and a 2048-point FFT, so caches of samples the current settings would analyze differently are skipped, as are
+    /// unreadable ones.
+    /// Returns how many caches were imported and skipped.
+    pub fn import_straycat(dir: &Path, config: &Config) -> Result<(usize, usize)> {
+        let db_path = Self::path_in(dir)?;
+        let mut db = Self::open_or_new(&db_path);
 
-        Ok(())
+        let mut files = vec![];
+        find_samples(dir, &mut files)?;
+
+        let (mut imported, mut skipped) = (0, 0);
+        for file in files {
+            let cache = file.with_extension("sc.npz");
+            let Some(key) = Self::key(dir, &file).filter(|_| cache.exists()) else {
+                continue;
+            };
+
+            let mut features = match npz::read(&cache) {
+                Ok(features) => features,
+                Err(e) => {
+                    log::warn!("Skipping unreadable {:?}: {}", cache, e);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let native_rate = hound::WavReader::open(&file)?.spec().sample_rate;
+            let base_source = Features::base_source(&file, config);
+            let analysis = Features::analysis_params(config, native_rate, Features::f0_source(&file, config)?, base_source);
+            let dims = features.mgc.first().map_or(analysis.mgc_dims, |row| row.len() as i32);
+            let matches = (analysis.frame_period, analysis.sample_rate, analysis.fft_size, analysis.mgc_dims)
+                == (features.frame_period, features.sample_rate, features.fft_size, dims);
+            if !matches || analysis.f0_source != F0Source::Harvest || analysis.f0_postprocess != PostProcess::default() {
+                log::warn!("Skipping {:?}: the current settings analyze {} differently", cache, key);
+                skipped += 1;
+                continue;
+            }
+
+            // Only the base frequency depends on where the base pitch comes from
+            if let Some(note) = analysis.base_source.note() {
+                features.base = mtof(note);
+            }
+            let entry = DatabaseEntry { analysis: Some(analysis), source: Some(SourceInfo::of(&file)?), features };
+            db.insert(key, entry);
+            imported += 1;
+        }
+
+        db.to_file(&db_path, &config.cache)?;
+        Ok((imported, skipped))
     }
 
+    /// Write UTAU `.frq` maps from the analyzed F0 of the samples under `dir`, for resamplers that read them.
+    /// Samples that already have one, perhaps hand-corrected, are left alone. Returns how many were written.
+    pub fn write_frq(&self, dir: &Path, config: &Config) -> Result<usize> {
+        let mut written = 0;
+
+        for key in self.keys() {
+            let file = dir.join(key);
+            let path = Frq::path_for(&file);
+            if path.exists() || !file.exists() {
+                continue;
+            }
+
+            let Some(features) = self.get(key)? else {
+                continue;
+            };
+            let (samples, sample_rate) = read_wav(&file, config.input.channel)?;
+            Frq::from_f0(&features.f0, features.frame_period, &samples, sample_rate).write(&path)?;
+            written += 1;
+        }
+
+        Ok(written)
+    }
+
+    /// Where the database of the voicebank at `dir` is kept
+    fn path_in(dir: &Path) -> Result<PathBuf> {
+        let name = dir.file_name().and_then(|n| n.to_str()).ok_or_else(|| anyhow!("{:?} is not a voicebank folder", dir))?;
+        Ok(dir.join(format!("{}.scx", name)))
+    }
+
+    /// The database at `path` if there is a readable one, so it can be updated, or an empty one otherwise
+    fn open_or_new(path: &PathBuf) -> Self {
+        if !path.exists() {
+            return Self::new(HashMap::new());
+        }
+
+        Self::from_file(path).unwrap_or_else(|e| {
+            log::warn!("Replacing unreadable database {:?}: {}", path, e);
+            Self::new(HashMap::new())
+        })
+    }
+
+    /// Write the database as an indexed archive, storing new entries as `cache` says.
+    /// Archived entries are copied without being decoded, and the database is consumed so its mapping is
+    /// released before the new archive replaces the file, which Windows refuses while it is mapped.
+    pub fn to_file(self, path: &Path, cache: &CacheConfig) -> Result<()> {
+        let FeatureDatabase { archive, added, removed } = self;
+        let mut entries: Vec<(String, Vec<u8>)> = vec![];
+
+        if let Some(archive) = archive {
+            for key in archive.keys().filter(|k| !added.contains_key(*k) && !removed.contains(*k)) {
+                entries.push((key.clone(), archive.raw(key).unwrap().to_vec()));
+            }
+        }
+        for (key, entry) in added {
+            let stored = bincode::serialize(&StoredEntry::new(&entry, cache))?;
+            entries.push((key, scx::compress(&stored, Compression::Zstd, cache.compression_level)?));
+        }
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        scx::write_archive(path, &Header::new(Kind::Database, None, None), &entries)
+    }
+
+    /// Open a database, reading only its index
     pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let data = decode(path)?;
+        if let Some(archive) = Archive::open(path)? {
+            return Ok(FeatureDatabase { archive: Some(archive), added: HashMap::new(), removed: HashSet::new() });
+        }
+
+        match scx::read(path)? {
+            (Some(_), _) => bail!("{:?} holds the features of a single sample, not a voicebank database", path),
+            (None, _) => bail!("{:?} is not a versioned straycrab feature database; run `straycrab analyze` to rebuild it", path),
+        }
+    }
 
-        // let data = std::fs::read(path)?;
-        let features: FeatureDatabase = bincode::deserialize(&data)?;
+    pub fn get(&self, key: &str) -> Result<Option<Features>> {
+        Ok(self.entry(key)?.map(|entry| entry.features))
+    }
 
-        Ok(features)
+    pub fn entry(&self, key: &str) -> Result<Option<DatabaseEntry>> {
+        if let Some(entry) = self.added.get(key) {
+            return Ok(Some(entry.clone()));
+        }
+        if self.removed.contains(key) {
+            return Ok(None);
+        }
+
+        let stored = self.archive.as_ref().map(|a| a.get::<StoredEntry>(key)).transpose()?.flatten();
+        Ok(stored.map(DatabaseEntry::from))
+    }
+
+    /// The features of `file` under `key`, if they were analyzed from the same audio with the same parameters.
+    /// Stale and unreadable entries give `None`, so the caller reanalyzes them.
+    pub fn get_fresh(&self, key: &str, file: &Path, config: &Config) -> Result<Option<Features>> {
+        Ok(self.fresh_entry(key, file, config)?.map(|(entry, _)| entry.features))
+    }
+
+    /// Like `get_fresh`, giving the whole entry and whether the sample was only touched, in which case the
+    /// entry records its new modification time
+    fn fresh_entry(&self, key: &str, file: &Path, config: &Config) -> Result<Option<(DatabaseEntry, bool)>> {
+        let entry = match self.entry(key) {
+            Ok(Some(entry)) => entry,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                log::warn!("Reanalyzing unreadable database entry {}: {}", key, e);
+                return Ok(None);
+            }
+        };
+
+        match Features::freshness(file, config, entry.analysis.as_ref(), entry.source.as_ref())? {
+            Freshness::Stale(reason) => {
+                log::info!("Reanalyzing database entry {}: {}", key, reason);
+                Ok(None)
+            }
+            Freshness::Fresh => Ok(Some((entry, false))),
+            Freshness::Touched(source) => Ok(Some((DatabaseEntry { source: Some(source), ..entry }, true))),
+        }
+    }
+
+    pub fn insert(&mut self, key: String, entry: DatabaseEntry) {
+        self.removed.remove(&key);
+        self.added.insert(key, entry);
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.added.remove(key);
+        if self.archive.as_ref().is_some_and(|a| a.raw(key).is_some()) {
+            self.removed.insert(key.to_string());
+        }
+    }
+
+    pub fn keys(&self) -> Vec<&str> {
+        let archived = self.archive.iter().flat_map(|a| a.keys())
+            .filter(|k| !self.added.contains_key(*k) && !self.removed.contains(*k));
+        let mut keys = archived.chain(self.added.keys()).map(|k| k.as_str()).collect::<Vec<&str>>();
+        keys.sort();
+        keys
     }
+}
 
-    pub fn get(&self, key: &str) -> Option<&Features> {
-        self.features.get(key)
+impl std::fmt::Debug for FeatureDatabase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FeatureDatabase").field("entries", &self.keys().len()).finish()
     }
 }
 
+/// How cached features compare to the sample they were analyzed from
+enum Freshness {
+    Fresh,
+    /// Same audio under a new modification time, recorded in the returned source
+    Touched(SourceInfo),
+    Stale(&'static str),
+}
+
+/// Whether `generate` takes F0 from UTAU's `.frq` maps instead of Harvest
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FrqUsage {
+    #[default]
+    Never,
+    /// For samples that have one
+    IfPresent,
+    /// Failing for samples that don't
+    Always,
+}
+
+/// Estimator `generate` runs when F0 doesn't come from a `.frq`
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum F0Estimator {
+    #[default]
+    Harvest,
+    /// DIO refined by StoneMask, several times faster but less robust
+    Dio,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct F0Config {
+    pub frq: FrqUsage,
+    pub estimator: F0Estimator,
+    /// Cleanup applied to the contour, whatever its source
+    #[serde(flatten)]
+    pub postprocess: PostProcess,
+}
+
+impl F0Config {
+    pub fn validate(&self) -> Result<()> {
+        self.postprocess.validate()
+    }
+}
+
+/// Analysis presets, which `fft_size`, `mgc_dims` and `d4c_threshold` override when given
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Quality {
+    /// 32 cepstral dimensions, for small caches and quick previews
+    Draft,
+    /// 64 cepstral dimensions
+    #[default]
+    Standard,
+    /// 128 cepstral dimensions and twice the FFT size
+    High,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct AnalysisConfig {
+    pub quality: Quality,
+    /// CheapTrick FFT size, which the envelope is also decoded to for synthesis. By default the smallest that
+    /// resolves `F0_FLOOR` at the analysis sample rate.
+    pub fft_size: Option<i32>,
+    /// Mel-cepstral dimensions the spectral envelope is stored with
+    pub mgc_dims: Option<i32>,
+    /// D4C voicing threshold from 0 to 1; frames scoring below it are synthesized as unvoiced
+    pub d4c_threshold: Option<f64>,
+}
+
+impl AnalysisConfig {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(fft_size) = self.fft_size {
+            if !(1024..=32768).contains(&fft_size) || fft_size.count_ones() != 1 {
+                bail!("analysis fft_size must be a power of two between 1024 and 32768, got {}", fft_size);
+            }
+        }
+        if let Some(dims) = self.mgc_dims {
+            if !(16..=256).contains(&dims) {
+                bail!("analysis mgc_dims must be between 16 and 256, got {}", dims);
+            }
+        }
+        if let Some(threshold) = self.d4c_threshold {
+            if !(0.0..=1.0).contains(&threshold) {
+                bail!("analysis d4c_threshold must be between 0 and 1, got {}", threshold);
+            }
+        }
 
+        Ok(())
+    }
+
+    /// FFT size for analyzing and synthesizing at `sample_rate`
+    pub fn fft_size(&self, sample_rate: i32) -> i32 {
+        self.fft_size.unwrap_or(match self.quality {
+            Quality::High => 2 * fft_size_for(sample_rate),
+            _ => fft_size_for(sample_rate),
+        })
+    }
+
+    pub fn mgc_dims(&self) -> i32 {
+        self.mgc_dims.unwrap_or(match self.quality {
+            Quality::Draft => 32,
+            Quality::Standard => 64,
+            Quality::High => 128,
+        })
+    }
+
+    pub fn d4c_threshold(&self) -> f64 {
+        self.d4c_threshold.unwrap_or(D4C_THRESHOLD)
+    }
+}
+
+/// Where the base pitch of a sample comes from instead of its analyzed F0. Overrides always apply; pitch
+/// folders and `prefix.map` only when enabled, as they don't always describe the samples' actual pitch.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct BasePitchConfig {
+    /// Take the pitch of samples in folders named after a note, such as `C4/`
+    pub folders: bool,
+    /// Take the pitch of a sample's prefix or suffix from the voicebank's `prefix.map`
+    pub prefix_map: bool,
+    /// Note names keyed by sample name without extension, optionally with the folders it is in (`C4/_あ`)
+    pub overrides: BTreeMap<String, String>,
+}
+
+impl BasePitchConfig {
+    pub fn validate(&self) -> Result<()> {
+        for (sample, note) in &self.overrides {
+            if parse_note(note).is_none() {
+                bail!("base pitch override of {} must be a note name such as C4, got {:?}", sample, note);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The override for `file`, from the key matching most of the end of its path
+    fn override_for(&self, file: &Path) -> Option<f64> {
+        let path = file.with_extension("");
+        let components = path.iter().rev().map(|c| c.to_string_lossy()).collect::<Vec<_>>();
+
+        let (_, note) = self.overrides.iter()
+            .filter(|(key, _)| {
+                let key = key.split('/').rev().collect::<Vec<&str>>();
+                key.len() <= components.len() && key.iter().zip(&components).all(|(k, c)| *k == c)
+            })
+            .max_by_key(|(key, _)| key.split('/').count())?;
+
+        parse_note(note)
+    }
+}
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+/// Collect the `.wav` files under `dir`, recursively
+fn find_samples(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            find_samples(&path, files)?;
+        } else if path.extension().is_some_and(|e| e.eq_ignore_ascii_case("wav")) {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleStatus {
+    Analyzed,
+    /// Unchanged since the last run
+    Skipped,
+    Failed,
+}
+
+/// Progress of `FeatureDatabase::generate`, printed as JSON lines with `analyze --json` for frontends
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Progress {
+    Start { total: usize },
+    Sample {
+        key: String,
+        status: SampleStatus,
+        /// Samples finished so far, including this one; samples finish out of order
+        done: usize,
+        total: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Finish { analyzed: usize, skipped: usize, failed: usize },
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Progress::Start { total } => write!(f, "Analyzing {} samples", total),
+            Progress::Sample { key, status, done, total, error } => {
+                write!(f, "[{}/{}] {}", done, total, key)?;
+                match (status, error) {
+                    (SampleStatus::Skipped, _) => write!(f, " (unchanged)"),
+                    (SampleStatus::Failed, Some(error)) => write!(f, " failed: {}", error),
+                    _ => Ok(()),
+                }
+            }
+            Progress::Finish { analyzed, skipped, failed } => {
+                write!(f, "Analyzed {} samples, {} unchanged, {} failed", analyzed, skipped, failed)
+            }
+        }
+    }
+}
+
+/// How features are stored, in full or quantized
+#[derive(Serialize, Deserialize)]
+enum StoredFeatures<'a> {
+    Full(Cow<'a, Features>),
+    Quantized(Box<QuantizedFeatures>),
+}
+
+impl<'a> StoredFeatures<'a> {
+    fn new(features: &'a Features, cache: &CacheConfig) -> StoredFeatures<'a> {
+        match cache.quantization {
+            Some(precision) => StoredFeatures::Quantized(Box::new(QuantizedFeatures::new(features, precision))),
+            None => StoredFeatures::Full(Cow::Borrowed(features)),
+        }
+    }
+
+    fn into_features(self) -> Features {
+        match self {
+            StoredFeatures::Full(features) => features.into_owned(),
+            StoredFeatures::Quantized(quantized) => quantized.features(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Features {
     pub base: f64,
+    pub frame_period: f64,
+    pub sample_rate: i32,
+    /// FFT size the spectral envelope was analyzed with, which decoding it has to use too
+    pub fft_size: i32,
     pub f0: Vec<f64>,
     pub mgc: Vec<Vec<f64>>,
     pub bap: Vec<Vec<f64>>
 }
 
 impl Features {
-    pub fn new(base: f64, f0: Vec<f64>, mgc: Vec<Vec<f64>>, bap: Vec<Vec<f64>>) -> Features {
+    pub fn new(base: f64, frame_period: f64, sample_rate: i32, fft_size: i32, f0: Vec<f64>, mgc: Vec<Vec<f64>>, bap: Vec<Vec<f64>>) -> Features {
         Features {
             base,
+            frame_period,
+            sample_rate,
+            fft_size,
             f0,
             mgc,
             bap,
         }
     }
     
-    pub fn to_file(&self, path: &PathBuf) -> Result<()> {
-        #[cfg(feature="zstd")] {
-            let serialized = bincode::serialize(&self)?;
-            encode(&serialized, path)?;
+    pub fn to_file(&self, path: &PathBuf, header: &Header, cache: &CacheConfig) -> Result<()> {
+        let serialized = bincode::serialize(&StoredFeatures::new(self, cache))?;
+        scx::write(path, header, &serialized, cache.compression_level)
+    }
+
+    /// Read features written by `to_file`. Unversioned files from before the container format were
+    /// analyzed with F0 and frame times swapped, and are rejected.
+    pub fn from_file(path: &PathBuf) -> Result<Self> {
+        match scx::read(path)? {
+            (Some(header), data) => Self::from_container(path, &header, &data),
+            (None, _) => bail!("{:?} is not a versioned straycrab feature file; delete it to regenerate", path),
         }
+    }
 
-        #[cfg(not(feature="zstd"))] {
-            let mut file = File::create(path)?;
-            let serialized = bincode::serialize(&self)?;
-            file.write_all(&serialized)?;
+    fn from_container(path: &Path, header: &Header, data: &[u8]) -> Result<Self> {
+        if header.kind != Kind::Features {
+            bail!("{:?} is a voicebank database, not the features of a single sample", path);
         }
 
-        Ok(())
+        Ok(deserialize::<StoredFeatures>(data, header.compression == Compression::Zstd)?.into_features())
     }
 
-    pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let data = if cfg!(feature="zstd") {
-            decode(path)?
-        } else {
-            std::fs::read(path)?
+    /// Parameters `generate` analyzes a `native_rate` Hz file with
+    pub fn analysis_params(config: &Config, native_rate: u32, f0_source: F0Source, base_source: BaseSource) -> AnalysisParams {
+        let sample_rate = config.input.sample_rate.unwrap_or(native_rate) as i32;
+
+        AnalysisParams {
+            frame_period: config.frame_period,
+            sample_rate,
+            fft_size: config.analysis.fft_size(sample_rate),
+            mgc_dims: config.analysis.mgc_dims(),
+            d4c_threshold: config.analysis.d4c_threshold(),
+            f0_floor: F0_FLOOR,
+            f0_ceil: F0_CEIL,
+            channel: config.input.channel,
+            f0_source,
+            f0_postprocess: config.f0.postprocess.clone(),
+            base_source,
+        }
+    }
+
+    /// Where `generate` takes the base pitch of `file` from: an override in `[base_pitch]`, then the folder it
+    /// is in and the closest `prefix.map` when enabled, and otherwise its analyzed F0
+    pub fn base_source(file: &Path, config: &Config) -> BaseSource {
+        let pitch = &config.base_pitch;
+        if let Some(note) = pitch.override_for(file) {
+            return BaseSource::Override(note);
+        }
+
+        let folder = file.parent().and_then(|dir| dir.file_name()).and_then(|name| parse_note(&name.to_string_lossy()));
+        if let Some(note) = folder.filter(|_| pitch.folders) {
+            return BaseSource::Folder(note);
+        }
+
+        if pitch.prefix_map {
+            let map = file.ancestors().skip(1).map(|dir| dir.join("prefix.map")).find(|map| map.is_file());
+            let stem = file.file_stem().unwrap_or_default().to_string_lossy();
+            match map.map(|map| PrefixMap::read(&map)) {
+                Some(Ok(map)) => if let Some(note) = map.base_note(&stem) {
+                    return BaseSource::PrefixMap(note);
+                },
+                Some(Err(e)) => log::warn!("Ignoring unreadable prefix.map for {:?}: {}", file, e),
+                None => {}
+            }
+        }
+
+        BaseSource::Estimated
+    }
+
+    /// Where `generate` takes the F0 of `file` from, following the `[f0] frq` and `estimator` settings
+    pub fn f0_source(file: &Path, config: &Config) -> Result<F0Source> {
+        let frq = Frq::path_for(file);
+        let estimated = match config.f0.estimator {
+            F0Estimator::Harvest => F0Source::Harvest,
+            F0Estimator::Dio => F0Source::Dio,
         };
 
-        let features: Features = bincode::deserialize(&data)?;
+        match config.f0.frq {
+            FrqUsage::Never => Ok(estimated),
+            FrqUsage::IfPresent if !frq.exists() => Ok(estimated),
+            _ => match scx::sha256(&frq) {
+                Ok(sha256) => Ok(F0Source::Frq(sha256)),
+                Err(e) => bail!("Failed to read frequency map {:?}: {}", frq, e),
+            },
+        }
+    }
+
+    /// Features of `file` from its own `.scx`, then its voicebank database, analyzing it when neither is fresh
+    /// or `force` is set. Samples analyzed here are cached next to them rather than added to the database,
+    /// which only `straycrab analyze` writes, so resampler processes running side by side never rewrite it.
+    pub fn load(file: &PathBuf, config: &Config, force: bool) -> Result<Self> {
+        let database = FeatureDatabase::find(file);
+        Self::load_from(file, config, force, database.as_ref().map(|(db, db_path)| (db, db_path.as_path())))
+    }
+
+    /// Features of each of the distinct `files`, like `load` without forcing. Samples without fresh features
+    /// are analyzed across all cores.
+    pub fn load_all(files: &[PathBuf], config: &Config) -> Result<Vec<Self>> {
+        let mut databases = HashMap::new();
+        let locations = files.iter().map(|file| {
+            let (db, db_path) = FeatureDatabase::find(file)?;
+            databases.entry(db_path.clone()).or_insert(db);
+            Some(db_path)
+        }).collect::<Vec<Option<PathBuf>>>();
+
+        files.par_iter().zip(&locations).map(|(file, location)| {
+            let database = location.as_ref().map(|db_path| (&databases[db_path], db_path.as_path()));
+            Self::load_from(file, config, false, database).map_err(|e| anyhow!("{:?}: {}", file, e))
+        }).collect()
+    }
+
+    /// `load` with the database at the given path that `file` belongs to, if any
+    fn load_from(file: &PathBuf, config: &Config, force: bool, database: Option<(&FeatureDatabase, &Path)>) -> Result<Self> {
+        // A sample named after the voicebank folder can't have a cache of its own, which would replace the database
+        let own_cache = database.is_none_or(|(_, db_path)| file.with_extension("scx") != db_path);
+
+        if !force {
+            // Per-file caches are only written when the database has no fresh entry, so they are the newer ones
+            if own_cache {
+                if let Some(features) = Self::from_cache(file, config)? {
+                    return Ok(features);
+                }
+            }
+            if let Some((db, db_path)) = database {
+                let key = FeatureDatabase::key(db_path.parent().unwrap(), file);
+                if let Some(features) = key.map(|key| db.get_fresh(&key, file, config)).transpose()?.flatten() {
+                    return Ok(features);
+                }
+            }
+        }
+
+        match own_cache {
+            true => Self::generate(file, config),
+            false => Ok(Self::analyze(file, config)?.0),
+        }
+    }
+
+    /// Read the cached features of `file`, if they were analyzed from the same audio with the same
+    /// parameters. Stale, unversioned and unreadable caches give `None`, so the caller regenerates them.
+    pub fn from_cache(file: &Path, config: &Config) -> Result<Option<Self>> {
+        Ok(Self::cached_entry(file, config)?.map(|entry| entry.features))
+    }
+
+    /// Like `from_cache`, along with the parameters and source audio the features were analyzed with
+    fn cached_entry(file: &Path, config: &Config) -> Result<Option<DatabaseEntry>> {
+        let path = file.with_extension("scx");
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let (mut header, data) = match scx::read(&path) {
+            Ok((Some(header), data)) => (header, data),
+            Ok((None, _)) => {
+                log::info!("Regenerating unversioned cache {:?}", path);
+                return Ok(None);
+            }
+            Err(e) => {
+                log::warn!("Regenerating unreadable cache {:?}: {}", path, e);
+                return Ok(None);
+            }
+        };
+
+        let touched = match Self::freshness(file, config, header.analysis.as_ref(), header.source.as_ref())? {
+            Freshness::Fresh => None,
+            Freshness::Touched(source) => Some(source),
+            Freshness::Stale(reason) => {
+                log::info!("Regenerating {:?}: {}", path, reason);
+                return Ok(None);
+            }
+        };
+
+        let features = match Self::from_container(&path, &header, &data) {
+            Ok(features) => features,
+            Err(e) => {
+                log::warn!("Regenerating unreadable cache {:?}: {}", path, e);
+                return Ok(None);
+            }
+        };
+
+        // Record the new modification time, so the audio isn't hashed again on every render
+        if let Some(source) = touched {
+            header.source = Some(source);
+            if let Err(e) = scx::rewrite_header(&path, &header, &data) {
+                log::warn!("Could not update {:?}: {}", path, e);
+            }
+        }
+
+        Ok(Some(DatabaseEntry { analysis: header.analysis, source: header.source, features }))
+    }
+
+    /// Whether features analyzed with `analysis` from `source` still match `file`
+    fn freshness(file: &Path, config: &Config, analysis: Option<&AnalysisParams>, source: Option<&SourceInfo>) -> Result<Freshness> {
+        let native_rate = hound::WavReader::open(file)?.spec().sample_rate;
+        let expected = Self::analysis_params(config, native_rate, Self::f0_source(file, config)?, Self::base_source(file, config));
+        if analysis != Some(&expected) {
+            return Ok(Freshness::Stale("analysis parameters changed"));
+        }
+
+        let Some(cached) = source else {
+            return Ok(Freshness::Stale("source audio not recorded"));
+        };
+
+        // Compare size and modification time first, and only hash the file when the time differs
+        let metadata = std::fs::metadata(file)?;
+        if metadata.len() != cached.size {
+            return Ok(Freshness::Stale("source audio changed"));
+        }
+        if scx::modified_nanos(&metadata)? == cached.modified {
+            return Ok(Freshness::Fresh);
+        }
+
+        let current = SourceInfo::of(file)?;
+        if current.sha256 != cached.sha256 {
+            return Ok(Freshness::Stale("source audio changed"));
+        }
+        Ok(Freshness::Touched(current))
+    }
+
+    /// Analyze `file` and cache the result next to it
+    pub fn generate(file: &PathBuf, config: &Config) -> Result<Self> {
+        let (features, analysis) = Self::analyze(file, config)?;
+
+        let header = Header::new(Kind::Features, Some(analysis), Some(SourceInfo::of(file)?));
+        features.to_file(&file.with_extension("scx"), &header, &config.cache)?;
 
         Ok(features)
     }
 
-    pub fn generate(file: &PathBuf) -> Result<Self> {
+    /// Analyze `file` without caching, returning the features and the parameters they were analyzed with
+    pub fn analyze(file: &Path, config: &Config) -> Result<(Self, AnalysisParams)> {
         let mut features = Self::default();
+        let (input, frame_period) = (&config.input, config.frame_period);
 
-        let (samples, sample_rate) = read_wav(file)?;
+        let (samples, native_rate) = read_wav(file, input.channel)?;
+        let (samples, sample_rate) = match input.sample_rate {
+            Some(rate) if rate != native_rate => {
+                log::info!("Converting {} Hz input to {} Hz for analysis", native_rate, rate);
+                (resample(&samples, native_rate, rate), rate)
+            }
+            _ => (samples, native_rate),
+        };
         let samples = samples.into_iter().map(|s| s as f64).collect::<Vec<f64>>();
+        let analysis = Self::analysis_params(config, sample_rate, Self::f0_source(file, config)?, Self::base_source(file, config));
+        let fft_size = analysis.fft_size;
 
-        log::info!("Generating F0 using Harvest");
-        let (f0, t) = rsworld::harvest(
-            &samples, 
-            sample_rate as i32,
-            &HarvestOption {
-                f0_floor: F0_FLOOR,
-                f0_ceil: F0_CEIL,
-                frame_period: 5.0
+        let (t, mut f0) = match analysis.f0_source {
+            F0Source::Harvest => {
+                log::info!("Generating F0 using Harvest");
+                rsworld::harvest(
+                    &samples,
+                    sample_rate as i32,
+                    &HarvestOption {
+                        f0_floor: analysis.f0_floor,
+                        f0_ceil: analysis.f0_ceil,
+                        frame_period
+                    }
+                )
             }
-        );
-        let base_f0 = base_frq(&f0, Some(F0_FLOOR), Some(F0_CEIL));
+            F0Source::Dio => {
+                log::info!("Generating F0 using DIO and StoneMask");
+                let (t, f0) = rsworld::dio(
+                    &samples,
+                    sample_rate as i32,
+                    &DioOption {
+                        f0_floor: analysis.f0_floor,
+                        f0_ceil: analysis.f0_ceil,
+                        frame_period,
+                        ..DioOption::new()
+                    }
+                );
+                let f0 = rsworld::stonemask(&samples, sample_rate as i32, &t, &f0);
+                (t, f0)
+            }
+            F0Source::Frq(_) => {
+                // On the frame grid Harvest would have used
+                let frq = Frq::path_for(file);
+                log::info!("Reading F0 from {:?}", frq);
+                let frames = (1000.0 * samples.len() as f64 / sample_rate as f64 / frame_period) as usize + 1;
+                let t = (0..frames).map(|i| i as f64 * frame_period / 1000.0).collect::<Vec<f64>>();
+                let f0 = Frq::read(&frq)?.f0_at(&t, native_rate);
+                (t, f0)
+            }
+        };
+        analysis.f0_postprocess.apply(&mut f0, frame_period);
+        let base_f0 = match analysis.base_source.note() {
+            Some(note) => mtof(note),
+            None => base_frq(&f0, Some(analysis.f0_floor), Some(analysis.f0_ceil)),
+        };
 
         log::info!("Generating spectral envelope");
         let mut ct_option = CheapTrickOption {
-            f0_floor: F0_FLOOR,
-            fft_size: 512,
+            f0_floor: analysis.f0_floor,
+            fft_size,
             q1: 0.0,
         };
 
@@ -149,11 +972,11 @@ impl Features {
             &f0,
             &mut ct_option
         );
-        let mgc = rsworld::code_spectral_envelope(&mgc, f0.len() as i32, DEFAULT_FS, get_fft_size(), 64);
+        let mgc = rsworld::code_spectral_envelope(&mgc, f0.len() as i32, sample_rate as i32, fft_size, analysis.mgc_dims);
 
         log::info!("Generating aperiodicity");
         let d4c_option = D4COption {
-            threshold: 0.25,
+            threshold: analysis.d4c_threshold,
         };
 
         let bap = rsworld::d4c(
@@ -163,22 +986,27 @@ impl Features {
             &f0,
             &d4c_option
         );
-        let bap = rsworld::code_aperiodicity(&bap, f0.len() as i32, DEFAULT_FS);
+        let bap = rsworld::code_aperiodicity(&bap, f0.len() as i32, sample_rate as i32);
 
         features.base = base_f0;
+        features.frame_period = frame_period;
+        features.sample_rate = sample_rate as i32;
+        features.fft_size = fft_size;
         features.f0 = f0;
         features.mgc = mgc;
         features.bap = bap;
 
-        features.to_file(&file.with_extension("scx"))?;
-
-        Ok(features)
+        Ok((features, analysis))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::io::audio::{write_wav, InputFormat, OutputFormat};
+    use crate::io::bin::DEFAULT_LEVEL;
+    use crate::io::quant::Precision;
+    use crate::util::testing::{harmonic_tone, temp};
     use dotenv::dotenv;
     use std::env;
 
@@ -189,7 +1017,7 @@ mod tests {
         let path = PathBuf::from(env::var("TEST_DIRECTORY").unwrap());
 
         let t0 = std::time::Instant::now();
-        let _ = FeatureDatabase::generate(&path).unwrap();
+        let _ = FeatureDatabase::generate(&path, &Config::default(), &|p| println!("{}", p)).unwrap();
         println!("Feature generation took: {:?}", t0.elapsed());
     }
 
@@ -203,7 +1031,7 @@ mod tests {
         let features = FeatureDatabase::from_file(&path).unwrap();
         println!("Feature reading took: {:?}", t0.elapsed());
 
-        features.get("_あ.wav");
+        features.get("_あ.wav").unwrap();
     }
 
     #[test]
@@ -213,11 +1041,483 @@ mod tests {
         let path = PathBuf::from(env::var("TEST_FILE").unwrap());
 
         let t0: std::time::Instant = std::time::Instant::now();
-        let _ = Features::generate(&path).unwrap();
+        let _ = Features::generate(&path, &Config::default()).unwrap();
         println!("Feature generation took: {:?}", t0.elapsed());
 
         let t1 = std::time::Instant::now();
         let _ = Features::from_file(&path.with_extension("scx")).unwrap();
         println!("Feature reading took: {:?}", t1.elapsed());
     }
+
+    #[test]
+    fn test_generate_at_file_sample_rate() {
+        let path = temp("tone_48k.wav");
+
+        // Half a second of an 800 Hz tone in 16-bit stereo, with the right channel silent
+        let spec = hound::WavSpec { channels: 2, sample_rate: 48000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for i in 0..24000 {
+            let s = (2.0 * std::f64::consts::PI * 800.0 * i as f64 / 48000.0).sin() * 0.5;
+            writer.write_sample((s * 32767.0) as i16).unwrap();
+            writer.write_sample(0i16).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let features = Features::generate(&path, &with_input(InputFormat { channel: Some(0), sample_rate: None })).unwrap();
+
+        assert_eq!(features.sample_rate, 48000);
+        assert_eq!(features.f0.len(), 101);
+        assert!((features.base - 800.0).abs() < 10.0, "base {}", features.base);
+
+        // The cache records how and from what it was made
+        let scx_path = path.with_extension("scx");
+        let (header, _) = scx::read(&scx_path).unwrap();
+        let header = header.unwrap();
+        assert_eq!(header.analysis.unwrap().sample_rate, 48000);
+        assert_eq!(header.source.unwrap().size, std::fs::metadata(&path).unwrap().len());
+        assert_eq!(Features::from_file(&scx_path).unwrap(), features);
+    }
+
+    #[test]
+    fn test_generate_at_configured_sample_rate() {
+        let path = temp("tone_48k_harmonic.wav");
+
+        // Half a second of a 220 Hz harmonic tone at 48 kHz, converted to the configured analysis rate first
+        let tone = harmonic_tone(220.0, 48000, 24000);
+        write_wav(&path, tone, &OutputFormat { sample_rate: 48000, ..OutputFormat::default() }).unwrap();
+
+        let features = Features::generate(&path, &with_input(InputFormat { channel: None, sample_rate: Some(44100) })).unwrap();
+
+        assert_eq!(features.sample_rate, 44100);
+        assert_eq!(features.f0.len(), 101);
+        assert!((features.base - 220.0).abs() < 5.0, "base {}", features.base);
+    }
+
+    #[test]
+    fn test_harvest_output_order() {
+        // rsworld::harvest returns frame times before F0; reading them the other way round analyzed the
+        // envelope and aperiodicity at F0 values taken as times
+        let path = temp("harvest_order.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&path, tone, &OutputFormat::default()).unwrap();
+
+        let (features, _) = Features::analyze(&path, &Config::default()).unwrap();
+        let near = features.f0.iter().filter(|f| (**f - 220.0).abs() < 5.0).count();
+        assert!(near > features.f0.len() * 4 / 5, "{:?}", features.f0);
+        assert!((features.base - 220.0).abs() < 5.0, "base {}", features.base);
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let path = temp("cached.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&path, tone.clone(), &OutputFormat::default()).unwrap();
+
+        let config = Config::default();
+        let base = Features::generate(&path, &config).unwrap().base;
+        let cached = |config: &Config| Features::from_cache(&path, config).unwrap().map(|f| f.base);
+        assert_eq!(cached(&config), Some(base));
+
+        // Different analysis parameters
+        assert_eq!(cached(&Config { frame_period: 10.0, ..Config::default() }), None);
+        assert_eq!(cached(&with_input(InputFormat { sample_rate: Some(48000), ..InputFormat::default() })), None);
+
+        // Touched but unchanged audio still matches by hash
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        assert_eq!(cached(&config), Some(base));
+
+        // and the header records the new time, so the next render doesn't hash it again
+        let source = scx::read(&path.with_extension("scx")).unwrap().0.unwrap().source.unwrap();
+        assert_eq!(source, SourceInfo::of(&path).unwrap());
+        assert_eq!(cached(&config), Some(base));
+
+        // Same size, different audio
+        let mut edited = tone;
+        edited[100] = 0.9;
+        write_wav(&path, edited, &OutputFormat::default()).unwrap();
+        assert_eq!(cached(&config), None);
+
+        // Unreadable caches are regenerated rather than failing the render
+        std::fs::write(path.with_extension("scx"), b"SCX\0garbage").unwrap();
+        assert_eq!(cached(&config), None);
+    }
+
+    fn with_input(input: InputFormat) -> Config {
+        Config { input, ..Config::default() }
+    }
+
+    fn features() -> Features {
+        Features::new(220.0, 5.0, 48000, 2048, vec![220.0; 3], vec![vec![0.5; 4]; 3], vec![vec![0.1; 2]; 3])
+    }
+
+    #[test]
+    fn test_reject_unversioned_files() {
+        let f = features();
+        // Pre-container layouts, analyzed with F0 and frame times swapped
+        let legacy = bincode::serialize(&(f.base, &f.f0, &f.mgc, &f.bap)).unwrap();
+        let path = temp("legacy_features.scx");
+        std::fs::write(&path, &legacy).unwrap();
+        assert!(Features::from_file(&path).unwrap_err().to_string().contains("delete it to regenerate"));
+
+        let db = bincode::serialize(&HashMap::from([("a.wav".to_string(), (f.base, &f.f0, &f.mgc, &f.bap))])).unwrap();
+        let path = temp("legacy_database.scx");
+        std::fs::write(&path, zstd::encode_all(&db[..], DEFAULT_LEVEL).unwrap()).unwrap();
+        assert!(FeatureDatabase::from_file(&path).unwrap_err().to_string().contains("straycrab analyze"));
+    }
+
+    #[test]
+    fn test_container_kinds() {
+        let db = FeatureDatabase::new(HashMap::from([("a.wav".to_string(), features())]));
+        let db_path = temp("kinds_database.scx");
+        db.to_file(&db_path, &CacheConfig::default()).unwrap();
+        assert_eq!(FeatureDatabase::from_file(&db_path).unwrap().get("a.wav").unwrap(), Some(features()));
+
+        let path = temp("kinds_features.scx");
+        features().to_file(&path, &Header::new(Kind::Features, None, None), &CacheConfig::default()).unwrap();
+        assert_eq!(Features::from_file(&path).unwrap(), features());
+
+        assert!(Features::from_file(&db_path).is_err());
+        assert!(FeatureDatabase::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_database_insertion() {
+        let path = temp("insertion.scx");
+        FeatureDatabase::new(HashMap::from([("a.wav".to_string(), features())])).to_file(&path, &CacheConfig::default()).unwrap();
+
+        // Add to and overwrite entries of a database opened from the file it is written back to. Writing
+        // consumes it, so its mapping is gone before the file is replaced, and it has to be opened again.
+        let mut db = FeatureDatabase::from_file(&path).unwrap();
+        let other = Features { base: 440.0, ..features() };
+        db.insert("C4/a.wav".to_string(), other.clone().into());
+        db.to_file(&path, &CacheConfig::default()).unwrap();
+        let mut db = FeatureDatabase::from_file(&path).unwrap();
+        db.insert("a.wav".to_string(), other.clone().into());
+        db.to_file(&path, &CacheConfig::default()).unwrap();
+
+        let db = FeatureDatabase::from_file(&path).unwrap();
+        assert_eq!(db.keys(), vec!["C4/a.wav", "a.wav"]);
+        assert_eq!(db.get("a.wav").unwrap(), Some(other.clone()));
+        assert_eq!(db.get("C4/a.wav").unwrap(), Some(other));
+        assert_eq!(db.get("b.wav").unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_database() {
+        let root = temp("bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        let sample = root.join("C4").join("a.wav");
+        std::fs::create_dir_all(sample.parent().unwrap()).unwrap();
+        let tone = harmonic_tone(220.0, 44100, 11025);
+        write_wav(&sample, tone, &OutputFormat::default()).unwrap();
+
+        assert!(FeatureDatabase::find(&sample).is_none());
+
+        // The per-file cache of `C4/C4.wav` is passed over for the database at the root
+        features().to_file(&root.join("C4").join("C4.scx"), &Header::new(Kind::Features, None, None), &CacheConfig::default()).unwrap();
+        let mut db = FeatureDatabase::new(HashMap::new());
+        db.insert("C4/a.wav".to_string(), features().into());
+        db.to_file(&root.join("bank.scx"), &CacheConfig::default()).unwrap();
+
+        let (mut db, path) = FeatureDatabase::find(&sample).unwrap();
+        assert_eq!(path, root.join("bank.scx"));
+        let key = FeatureDatabase::key(&root, &sample).unwrap();
+        assert_eq!(key, "C4/a.wav");
+
+        // Entries without provenance are reanalyzed, as are entries analyzed differently
+        let config = Config::default();
+        assert_eq!(db.get_fresh(&key, &sample, &config).unwrap(), None);
+
+        let entry = DatabaseEntry::analyze(&sample, &config).unwrap();
+        db.insert(key.clone(), entry.clone());
+        db.to_file(&path, &CacheConfig::default()).unwrap();
+
+        let (db, _) = FeatureDatabase::find(&sample).unwrap();
+        let stored = db.entry(&key).unwrap().unwrap();
+        assert_eq!((stored.analysis, stored.source), (entry.analysis, entry.source));
+        assert_eq!(db.get_fresh(&key, &sample, &config).unwrap().map(|f| f.base), Some(entry.features.base));
+        assert_eq!(db.get_fresh(&key, &sample, &Config { frame_period: 10.0, ..Config::default() }).unwrap(), None);
+        assert!(!sample.with_extension("scx").exists());
+    }
+
+    #[test]
+    fn test_generate_database() {
+        let root = temp("gen_bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        let tone = |freq: f32| harmonic_tone(freq, 44100, 11025);
+        for key in ["a.wav", "C4/a.wav", "A3/a.wav"] {
+            std::fs::create_dir_all(root.join(key).parent().unwrap()).unwrap();
+            write_wav(&root.join(key), tone(220.0), &OutputFormat::default()).unwrap();
+        }
+        std::fs::write(root.join("broken.wav"), b"not audio").unwrap();
+
+        let run = || {
+            let events = std::sync::Mutex::new(vec![]);
+            let db = FeatureDatabase::generate(&root, &Config::default(), &|p| events.lock().unwrap().push(p.clone())).unwrap();
+            (db, events.into_inner().unwrap())
+        };
+
+        // Pitch folders don't collide, and samples get no `.scx` of their own
+        let (db, events) = run();
+        assert_eq!(db.keys(), vec!["A3/a.wav", "C4/a.wav", "a.wav"]);
+        assert!(!root.join("a.scx").exists());
+        assert_eq!(events.first(), Some(&Progress::Start { total: 4 }));
+        assert_eq!(events.last(), Some(&Progress::Finish { analyzed: 3, skipped: 0, failed: 1 }));
+        assert_eq!(events.iter().filter(|p| matches!(p, Progress::Sample { .. })).count(), 4);
+        drop(db);
+
+        // Only changed samples are analyzed again, and removed ones are dropped
+        assert_eq!(run().1.last(), Some(&Progress::Finish { analyzed: 0, skipped: 3, failed: 1 }));
+        write_wav(&root.join("C4/a.wav"), tone(330.0), &OutputFormat::default()).unwrap();
+        std::fs::remove_file(root.join("A3/a.wav")).unwrap();
+
+        let (db, events) = run();
+        assert_eq!(events.last(), Some(&Progress::Finish { analyzed: 1, skipped: 1, failed: 1 }));
+        assert_eq!(db.keys(), vec!["C4/a.wav", "a.wav"]);
+        assert!((db.get("C4/a.wav").unwrap().unwrap().base - 330.0).abs() < 5.0);
+        assert_eq!(FeatureDatabase::from_file(&root.join("gen_bank.scx")).unwrap().keys(), vec!["C4/a.wav", "a.wav"]);
+
+        // Samples rendered before being analyzed leave the database alone, and their caches are taken over
+        let sample = root.join("C4/b.wav");
+        write_wav(&sample, tone(330.0), &OutputFormat::default()).unwrap();
+        let rendered = Features::load(&sample, &Config::default(), false).unwrap();
+        assert!(sample.with_extension("scx").exists());
+        assert_eq!(FeatureDatabase::from_file(&root.join("gen_bank.scx")).unwrap().keys(), vec!["C4/a.wav", "a.wav"]);
+
+        let (db, events) = run();
+        assert_eq!(events.last(), Some(&Progress::Finish { analyzed: 1, skipped: 2, failed: 1 }));
+        assert_eq!(db.get("C4/b.wav").unwrap(), Some(rendered));
+        assert!(!sample.with_extension("scx").exists());
+        drop(db);
+
+        // Touched samples count as unchanged, but their entries record the new modification time
+        let file = std::fs::File::options().write(true).open(&sample).unwrap();
+        file.set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(10)).unwrap();
+        let (db, events) = run();
+        assert_eq!(events.last(), Some(&Progress::Finish { analyzed: 0, skipped: 3, failed: 1 }));
+        assert_eq!(db.entry("C4/b.wav").unwrap().unwrap().source, Some(SourceInfo::of(&sample).unwrap()));
+    }
+
+    #[test]
+    fn test_regenerate_database() {
+        let root = temp("regen_bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        write_wav(&root.join("a.wav"), harmonic_tone(220.0, 44100, 11025), &OutputFormat::default()).unwrap();
+
+        let run = || {
+            let events = std::sync::Mutex::new(vec![]);
+            let db = FeatureDatabase::generate(&root, &Config::default(), &|p| events.lock().unwrap().push(p.clone())).unwrap();
+            // Compared serialized, as unvoiced frames can hold NaN
+            (bincode::serialize(&db.get("a.wav").unwrap().unwrap()).unwrap(), events.into_inner().unwrap().pop())
+        };
+
+        // Each run maps the existing database, and releases it before replacing the file, like the returned
+        // database is released here between runs
+        let (first, _) = run();
+        let (second, finish) = run();
+        assert_eq!(finish, Some(Progress::Finish { analyzed: 0, skipped: 1, failed: 0 }));
+        assert_eq!(second, first);
+        let stored = FeatureDatabase::from_file(&root.join("regen_bank.scx")).unwrap().get("a.wav").unwrap().unwrap();
+        assert_eq!(bincode::serialize(&stored).unwrap(), first);
+    }
+
+    #[test]
+    fn test_import_straycat() {
+        let root = temp("cat_bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("C4")).unwrap();
+
+        // straycat caches next to a 44.1 kHz and a 48 kHz sample, and one without a cache
+        let tone = vec![0.1; 4410];
+        let cached = Features::new(220.0, 5.0, 44100, 2048, vec![220.0; 3], vec![vec![0.5; 64]; 3], vec![vec![0.1; 5]; 3]);
+        write_wav(&root.join("C4/a.wav"), tone.clone(), &OutputFormat::default()).unwrap();
+        npz::write(&root.join("C4/a.sc.npz"), &cached).unwrap();
+        write_wav(&root.join("b.wav"), tone.clone(), &OutputFormat { sample_rate: 48000, ..OutputFormat::default() }).unwrap();
+        npz::write(&root.join("b.sc.npz"), &cached).unwrap();
+        write_wav(&root.join("c.wav"), tone, &OutputFormat::default()).unwrap();
+
+        let config = Config::default();
+        assert_eq!(FeatureDatabase::import_straycat(&root, &config).unwrap(), (1, 1));
+
+        // Imported caches count as fresh for the resampler
+        let sample = root.join("C4/a.wav");
+        let (db, _) = FeatureDatabase::find(&sample).unwrap();
+        assert_eq!(db.keys(), vec!["C4/a.wav"]);
+        assert_eq!(db.get_fresh("C4/a.wav", &sample, &config).unwrap(), Some(cached.clone()));
+        assert_eq!(Features::load(&sample, &config, false).unwrap(), cached);
+    }
+
+    #[test]
+    fn test_frq_source() {
+        let root = temp("frq_bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let sample = root.join("a.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&sample, tone, &OutputFormat::default()).unwrap();
+
+        // Maps are written once from the analyzed F0, and never overwritten
+        let config = Config::default();
+        let db = FeatureDatabase::generate(&root, &config, &|_| {}).unwrap();
+        assert_eq!(db.write_frq(&root, &config).unwrap(), 1);
+        assert_eq!(db.write_frq(&root, &config).unwrap(), 0);
+
+        let frq_path = Frq::path_for(&sample);
+        let mut frq = Frq::read(&frq_path).unwrap();
+        assert!((frq.average - 220.0).abs() < 5.0, "average {}", frq.average);
+
+        // A hand-corrected map takes over from Harvest when enabled, and invalidates the cache
+        frq.f0.iter_mut().filter(|f| **f > 0.0).for_each(|f| *f = 300.0);
+        frq.write(&frq_path).unwrap();
+        assert!(db.get_fresh("a.wav", &sample, &config).unwrap().is_some());
+
+        let frq_config = Config { f0: F0Config { frq: FrqUsage::IfPresent, ..F0Config::default() }, ..Config::default() };
+        assert!(db.get_fresh("a.wav", &sample, &frq_config).unwrap().is_none());
+        let (features, analysis) = Features::analyze(&sample, &frq_config).unwrap();
+        assert!(matches!(analysis.f0_source, F0Source::Frq(_)));
+        assert!((features.base - 300.0).abs() < 1.0, "base {}", features.base);
+        assert_eq!(features.f0.len(), db.get("a.wav").unwrap().unwrap().f0.len());
+
+        // Required maps have to exist
+        std::fs::remove_file(&frq_path).unwrap();
+        assert_eq!(Features::f0_source(&sample, &frq_config).unwrap(), F0Source::Harvest);
+        let always = Config { f0: F0Config { frq: FrqUsage::Always, ..F0Config::default() }, ..Config::default() };
+        assert!(Features::analyze(&sample, &always).is_err());
+    }
+
+    #[test]
+    fn test_base_source() {
+        let root = temp("base_pitch_bank/");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("C4")).unwrap();
+        std::fs::write(root.join("prefix.map"), "A4\t\t↑\nB4\t\t↑\nC4\t\t\n").unwrap();
+        let sample = root.join("C4/a.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&sample, tone, &OutputFormat::default()).unwrap();
+
+        // Nothing but explicit overrides is used unless enabled
+        let mut config = Config::default();
+        assert_eq!(Features::base_source(&sample, &config), BaseSource::Estimated);
+        config.base_pitch.prefix_map = true;
+        assert_eq!(Features::base_source(&root.join("C4/か↑.wav"), &config), BaseSource::PrefixMap(70.0));
+        config.base_pitch.folders = true;
+        assert_eq!(Features::base_source(&root.join("C4/か↑.wav"), &config), BaseSource::Folder(60.0));
+        config.base_pitch.overrides = BTreeMap::from([("a".to_string(), "D4".to_string()), ("C4/a".to_string(), "E4".to_string())]);
+        assert_eq!(Features::base_source(&sample, &config), BaseSource::Override(64.0));
+
+        // The source is recorded, and changing it invalidates the cache
+        let (features, analysis) = Features::analyze(&sample, &config).unwrap();
+        assert_eq!(analysis.base_source, BaseSource::Override(64.0));
+        assert!((features.base - mtof(64.0)).abs() < 1e-9);
+        let mut db = FeatureDatabase::new(HashMap::new());
+        db.insert("C4/a.wav".to_string(), DatabaseEntry { analysis: Some(analysis), source: Some(SourceInfo::of(&sample).unwrap()), features });
+        assert!(db.get_fresh("C4/a.wav", &sample, &config).unwrap().is_some());
+        assert!(db.get_fresh("C4/a.wav", &sample, &Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_f0_estimators() {
+        let dir = temp("estimators/");
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = dir.join("a.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&sample, tone, &OutputFormat::default()).unwrap();
+
+        let harvest = Config::default();
+        let dio = Config { f0: F0Config { estimator: F0Estimator::Dio, ..F0Config::default() }, ..Config::default() };
+        let (by_harvest, _) = Features::analyze(&sample, &harvest).unwrap();
+        let (by_dio, analysis) = Features::analyze(&sample, &dio).unwrap();
+        assert_eq!(analysis.f0_source, F0Source::Dio);
+        assert_eq!(by_dio.f0.len(), by_harvest.f0.len());
+        assert!((by_dio.base - 220.0).abs() < 2.0, "base {}", by_dio.base);
+
+        // Changing the estimator or the cleanup invalidates the cache
+        Features::generate(&sample, &harvest).unwrap();
+        assert!(Features::from_cache(&sample, &harvest).unwrap().is_some());
+        assert!(Features::from_cache(&sample, &dio).unwrap().is_none());
+        let smoothed = Config { f0: F0Config { postprocess: PostProcess { median_window: 25.0, ..PostProcess::default() }, ..F0Config::default() }, ..Config::default() };
+        assert!(Features::from_cache(&sample, &smoothed).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_analysis_quality() {
+        let dir = temp("quality/");
+        std::fs::create_dir_all(&dir).unwrap();
+        let sample = dir.join("a.wav");
+        let tone = (0..22050).map(|i| (2.0 * std::f32::consts::PI * 220.0 * i as f32 / 44100.0).sin() * 0.3).collect::<Vec<f32>>();
+        write_wav(&sample, tone, &OutputFormat::default()).unwrap();
+
+        let quality = |quality| Config { analysis: AnalysisConfig { quality, ..AnalysisConfig::default() }, ..Config::default() };
+        let (draft, analysis) = Features::analyze(&sample, &quality(Quality::Draft)).unwrap();
+        assert_eq!((analysis.fft_size, analysis.mgc_dims, draft.mgc[0].len()), (2048, 32, 32));
+
+        // The header records the settings, so switching presets reanalyzes
+        Features::generate(&sample, &quality(Quality::High)).unwrap();
+        let (header, _) = scx::read(&sample.with_extension("scx")).unwrap();
+        let analysis = header.unwrap().analysis.unwrap();
+        assert_eq!((analysis.fft_size, analysis.mgc_dims), (4096, 128));
+        assert!(Features::from_cache(&sample, &quality(Quality::High)).unwrap().is_some());
+        assert!(Features::from_cache(&sample, &quality(Quality::Standard)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_quantized_storage() {
+        let original = Features::new(220.0, 5.0, 44100, 2048, vec![0.0, 219.0, 221.5], vec![vec![-9.0, 0.25, 0.5]; 3], vec![vec![-30.0], vec![-1.0], vec![-2.0]]);
+        let int8 = CacheConfig { quantization: Some(Precision::Int8), ..CacheConfig::default() };
+        let close = |a: &Features, b: &Features| a.f0.iter().zip(&b.f0).all(|(x, y)| (x - y).abs() < 0.1)
+            && a.bap.iter().flatten().zip(b.bap.iter().flatten()).all(|(x, y)| (x - y).abs() < 0.1)
+            && a.mgc == b.mgc;
+
+        let path = temp("quantized.scx");
+        original.to_file(&path, &Header::new(Kind::Features, None, None), &int8).unwrap();
+        let restored = Features::from_file(&path).unwrap();
+        assert_ne!(restored, original);
+        assert!(close(&restored, &original), "{:?}", restored);
+
+        let db_path = temp("quantized_db.scx");
+        FeatureDatabase::new(HashMap::from([("a.wav".to_string(), original.clone())])).to_file(&db_path, &int8).unwrap();
+        assert!(close(&FeatureDatabase::from_file(&db_path).unwrap().get("a.wav").unwrap().unwrap(), &original));
+    }
+
+    #[test]
+    fn test_progress_json() {
+        let sample = Progress::Sample { key: "C4/a.wav".to_string(), status: SampleStatus::Skipped, done: 2, total: 10, error: None };
+        assert_eq!(serde_json::to_string(&sample).unwrap(), r#"{"event":"sample","key":"C4/a.wav","status":"skipped","done":2,"total":10}"#);
+
+        let finish = Progress::Finish { analyzed: 1, skipped: 2, failed: 0 };
+        assert_eq!(serde_json::to_string(&finish).unwrap(), r#"{"event":"finish","analyzed":1,"skipped":2,"failed":0}"#);
+    }
+
+    /// Run with `cargo test --release bench_database_lookup -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_database_lookup() {
+        // 2,000 samples of one second each at 5 ms, with 64 mgc and 5 bap dimensions
+        let entries = (0..2000).map(|i| {
+            let f0 = (0..200).map(|j| 220.0 + ((i + j) as f64 * 0.1).sin()).collect::<Vec<f64>>();
+            let mgc = (0..200).map(|j| (0..64).map(|k| ((i * j + k) as f64 * 0.01).cos()).collect()).collect();
+            let bap = (0..200).map(|j| (0..5).map(|k| ((i + j * k) as f64 * 0.02).sin()).collect()).collect();
+            (format!("C4/{}.wav", i), Features::new(220.0, 5.0, 44100, 2048, f0, mgc, bap))
+        }).collect::<HashMap<String, Features>>();
+
+        let path = temp("bench_indexed.scx");
+        FeatureDatabase::new(entries).to_file(&path, &CacheConfig::default()).unwrap();
+
+        let t0 = std::time::Instant::now();
+        let db = FeatureDatabase::from_file(&path).unwrap();
+        let single = db.get("C4/1234.wav").unwrap().unwrap();
+        let lookup_time = t0.elapsed();
+
+        let t1 = std::time::Instant::now();
+        let db = FeatureDatabase::from_file(&path).unwrap();
+        let all = db.keys().into_iter().map(|key| db.get(key).unwrap().unwrap()).collect::<Vec<Features>>();
+        let decode_time = t1.elapsed();
+
+        println!("Single lookup: {:?}", lookup_time);
+        println!("Decoding all {} entries: {:?}", all.len(), decode_time);
+        assert!(all.contains(&single));
+        assert!(lookup_time < decode_time);
+    }
 }
\ No newline at end of file
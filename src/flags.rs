@@ -1,4 +1,29 @@
+use std::collections::BTreeMap;
+
 use num_traits::Pow;
+use regex::Regex;
+
+use crate::stretch::StretchMode;
+
+/// Flags understood by the resampler. Two-letter names come first so the tokenizer prefers them.
+pub const FLAGS: [(&str, &str); 16] = [
+    ("fe", "Vocal fry end (ms)"),
+    ("fl", "Vocal fry length (ms)"),
+    ("fo", "Vocal fry offset (ms)"),
+    ("fv", "Vocal fry volume"),
+    ("fp", "Vocal fry pitch (Hz)"),
+    ("ve", "Voicing transition"),
+    ("vo", "Voicing offset"),
+    ("st", "Stretch mode (0 interpolate, 1 loop, 2 ping-pong, 3 random segments)"),
+    ("g", "Gender"),
+    ("B", "Breathiness"),
+    ("P", "Peak compression"),
+    ("p", "Peak normalization"),
+    ("A", "Tremolo"),
+    ("t", "Pitch offset (semitones)"),
+    ("S", "Sibilance"),
+    ("G", "Force feature regeneration"),
+];
 
 #[derive(Debug, Copy, Clone, PartialEq, Default)]
 pub struct ResamplerFlags {
@@ -16,6 +41,7 @@ pub struct ResamplerFlags {
     pub peak_compression: Option<i32>,
     pub peak_normalization: Option<i32>,
     pub sibilance: Option<i32>,
+    pub stretch_mode: Option<StretchMode>,
     pub force_features: bool
 }
 
@@ -23,32 +49,161 @@ impl ResamplerFlags {
     pub fn parse(flags: &str) -> ResamplerFlags {
         let mut res = ResamplerFlags::default();
 
-        for f in flags.split("|").collect::<Vec<&str>>() {
-            let parts = f.split(' ').collect::<Vec<&str>>();
-            let (flag, value) = (parts[0], parts.get(1));
-
-            let value: i32 = value.map(|v| v.parse().unwrap()).unwrap_or(0);
-
-            match flag {
-                "fe" => res.fry_end = Some(value as f64 / 1000.0),
-                "fl" => res.fry_length = Some((value as f64 / 1000.0).max(0.001)),
-                "fo" => res.fry_offset = Some(value as f64 / 1000.0),
-                "fv" => res.fry_volume = Some(value),
-                "fp" => res.fry_pitch = Some(value.max(0) as f64),
-                "ve" => res.voicing_transition = Some(value),
-                "vo" => res.voicing_offset = Some(value),
-                "g" => res.gender = Some((value as f64 / 120.0).pow(2.0)),
-                "B" => res.breathiness = Some(value),
-                "P" => res.peak_compression = Some(value),
-                "p" => res.peak_normalization = Some(value),
-                "A" => res.tremolo = Some(value),
-                "t" => res.pitch_offset = Some(value),
-                "S" => res.sibilance = Some(value),
-                "G" => res.force_features = true,
-                _ => {}
-            }
+        for (flag, value) in tokenize(flags, &BTreeMap::new()).1 {
+            res.set(&flag, value);
         }
 
         res
     }
+
+    /// Parse a flags string, expanding `@name` preset tokens first so explicit flags override them
+    pub fn parse_with_presets(flags: &str, presets: &BTreeMap<String, String>) -> ResamplerFlags {
+        let (used_presets, explicit) = tokenize(flags, presets);
+
+        let mut expanded = used_presets.iter().map(|name| presets[name].clone()).collect::<Vec<String>>();
+        expanded.extend(explicit.iter().map(|(flag, value)| format!("{}{}", flag, value)));
+
+        Self::parse(&expanded.join("|"))
+    }
+
+    fn set(&mut self, flag: &str, value: i32) {
+        match flag {
+            "fe" => self.fry_end = Some(value as f64 / 1000.0),
+            "fl" => self.fry_length = Some((value as f64 / 1000.0).max(0.001)),
+            "fo" => self.fry_offset = Some(value as f64 / 1000.0),
+            "fv" => self.fry_volume = Some(value),
+            "fp" => self.fry_pitch = Some(value.max(0) as f64),
+            "ve" => self.voicing_transition = Some(value),
+            "vo" => self.voicing_offset = Some(value),
+            "st" => self.stretch_mode = StretchMode::from_flag(value),
+            "g" => self.gender = Some((value as f64 / 120.0).pow(2.0)),
+            "B" => self.breathiness = Some(value),
+            "P" => self.peak_compression = Some(value),
+            "p" => self.peak_normalization = Some(value),
+            "A" => self.tremolo = Some(value),
+            "t" => self.pitch_offset = Some(value),
+            "S" => self.sibilance = Some(value),
+            "G" => self.force_features = true,
+            _ => {}
+        }
+    }
+}
+
+/// Split a flags string into the presets it references and its explicit `(flag, value)` pairs.
+/// Accepts both the compact UTAU form (`B60g-5`) and the separated form (`B 60|g -5`).
+fn tokenize(flags: &str, presets: &BTreeMap<String, String>) -> (Vec<String>, Vec<(String, i32)>) {
+    // Longest preset names first, so `@soft2` is not read as `@soft` followed by garbage
+    let mut names = presets.keys().map(|k| regex::escape(k)).collect::<Vec<String>>();
+    names.sort_by_key(|n| std::cmp::Reverse(n.len()));
+
+    // Where an unknown name ends can't be told, so it stops at the first character that isn't a lowercase
+    // letter and `@whisperB10` keeps its `B10`
+    let preset_pattern = if names.is_empty() {
+        r"@([a-z_]+)".to_string()
+    } else {
+        format!(r"@({})|@([a-z_]+)", names.join("|"))
+    };
+    let flag_pattern = FLAGS.iter().map(|(f, _)| *f).collect::<Vec<&str>>().join("|");
+    let re = Regex::new(&format!(r"{}|({})\s*([+-]?\d+)?", preset_pattern, flag_pattern)).unwrap();
+
+    let mut used = vec![];
+    let mut explicit = vec![];
+
+    // Capture groups shift depending on whether known preset names were given
+    let (known, unknown, flag, value) = if names.is_empty() { (None, 1, 2, 3) } else { (Some(1), 2, 3, 4) };
+
+    for caps in re.captures_iter(flags) {
+        if let Some(name) = known.and_then(|i| caps.get(i)) {
+            used.push(name.as_str().to_string());
+        } else if let Some(name) = caps.get(unknown) {
+            log::warn!("Unknown flag preset: {}", name.as_str());
+        } else if let Some(f) = caps.get(flag) {
+            let v = caps.get(value).map(|v| v.as_str().parse().unwrap_or(0)).unwrap_or(0);
+            explicit.push((f.as_str().to_string(), v));
+        }
+    }
+
+    (used, explicit)
+}
+
+/// Help text for the `flags` command, listing supported flags and configured presets
+pub fn help(presets: &BTreeMap<String, String>) -> String {
+    let mut res = String::from("Flags:\n");
+    for (flag, description) in FLAGS.iter() {
+        res.push_str(&format!("  {:<4}{}\n", flag, description));
+    }
+
+    res.push_str("\nPresets (use as @name):\n");
+    if presets.is_empty() {
+        res.push_str("  (none configured)\n");
+    }
+    for (name, flags) in presets {
+        res.push_str(&format!("  @{:<12}{}\n", name, flags));
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets() -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("soft".to_string(), "B60g-5P70".to_string()),
+            ("belt".to_string(), "B0P100".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_parse_utau_flags() {
+        let flags = ResamplerFlags::parse("B60g-5P70fe100G");
+
+        assert_eq!(flags.breathiness, Some(60));
+        assert_eq!(flags.gender, Some((-5.0f64 / 120.0).pow(2.0)));
+        assert_eq!(flags.peak_compression, Some(70));
+        assert_eq!(flags.fry_end, Some(0.1));
+        assert!(flags.force_features);
+    }
+
+    #[test]
+    fn test_stretch_mode_flag() {
+        assert_eq!(ResamplerFlags::parse("st2B10").stretch_mode, Some(StretchMode::PingPong));
+        assert_eq!(ResamplerFlags::parse("S10t2").stretch_mode, None);
+    }
+
+    #[test]
+    fn test_parse_separated_flags() {
+        let flags = ResamplerFlags::parse("fe 100|fl 50|t -2");
+
+        assert_eq!(flags.fry_end, Some(0.1));
+        assert_eq!(flags.fry_length, Some(0.05));
+        assert_eq!(flags.pitch_offset, Some(-2));
+    }
+
+    #[test]
+    fn test_preset_expansion() {
+        let flags = ResamplerFlags::parse_with_presets("@soft", &presets());
+
+        assert_eq!(flags, ResamplerFlags::parse("B60g-5P70"));
+    }
+
+    #[test]
+    fn test_explicit_flags_override_presets() {
+        // The preset is expanded first even when it appears after the explicit flag
+        let flags = ResamplerFlags::parse_with_presets("B20@softt1", &presets());
+
+        assert_eq!(flags.breathiness, Some(20));
+        assert_eq!(flags.peak_compression, Some(70));
+        assert_eq!(flags.pitch_offset, Some(1));
+    }
+
+    #[test]
+    fn test_unknown_preset_is_ignored() {
+        let flags = ResamplerFlags::parse_with_presets("@whisperB10", &presets());
+        assert_eq!(flags, ResamplerFlags::parse("B10"));
+
+        let flags = ResamplerFlags::parse_with_presets("@whisper|g-5", &BTreeMap::new());
+        assert_eq!(flags, ResamplerFlags::parse("g-5"));
+    }
 }
\ No newline at end of file
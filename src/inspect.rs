@@ -0,0 +1,291 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::dsp::f0::PostProcess;
+use crate::features::{DatabaseEntry, FeatureDatabase, Features};
+use crate::io::scx::{self, AnalysisParams, BaseSource, F0Source, Kind, SourceInfo};
+use crate::util::misc::{F0_CEIL, F0_FLOOR};
+
+/// What `straycrab inspect` found in a feature file or database
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub path: PathBuf,
+    pub kind: Kind,
+    /// Container format version
+    pub version: u16,
+    pub entries: Vec<EntryReport>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryReport {
+    /// Path within the voicebank, or the file name of a single sample's cache
+    pub key: String,
+    pub frames: usize,
+    /// Seconds
+    pub duration: f64,
+    pub sample_rate: i32,
+    pub frame_period: f64,
+    pub base: f64,
+    /// Share of frames with an F0
+    pub voiced_ratio: f64,
+    /// Lowest and highest F0 of the voiced frames
+    pub f0_min: Option<f64>,
+    pub f0_max: Option<f64>,
+    pub mgc_dims: usize,
+    pub bap_dims: usize,
+    pub analysis: Option<AnalysisSummary>,
+    pub source: Option<SourceSummary>,
+    pub issues: Vec<Issue>,
+}
+
+/// `AnalysisParams` with the F0 source spelled out
+#[derive(Debug, Serialize)]
+pub struct AnalysisSummary {
+    pub frame_period: f64,
+    pub sample_rate: i32,
+    pub fft_size: i32,
+    pub mgc_dims: i32,
+    pub d4c_threshold: f64,
+    pub f0_floor: f64,
+    pub f0_ceil: f64,
+    pub channel: Option<u16>,
+    /// `harvest`, `dio` or `frq:<sha256>`
+    pub f0_source: String,
+    pub f0_postprocess: PostProcess,
+    /// `estimated`, or `folder`, `prefix_map` or `override` with the MIDI note, e.g. `folder:60`
+    pub base_source: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SourceSummary {
+    pub size: u64,
+    pub modified: u64,
+    pub sha256: String,
+}
+
+/// Signs of a broken analysis
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Issue {
+    AllUnvoiced,
+    /// Base frequency outside `F0_FLOOR..F0_CEIL`
+    BaseOutOfRange,
+    /// NaN or infinite mel-cepstral coefficients
+    NonFiniteMgc,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::AllUnvoiced => write!(f, "all frames unvoiced"),
+            Issue::BaseOutOfRange => write!(f, "base frequency outside {}-{} Hz", F0_FLOOR, F0_CEIL),
+            Issue::NonFiniteMgc => write!(f, "NaN or infinite mgc"),
+        }
+    }
+}
+
+/// Inspect the `.scx` at `path`, either a single sample's features or a voicebank database
+pub fn inspect(path: &Path) -> Result<Report> {
+    let Some(header) = scx::read(path)?.0 else {
+        bail!("{:?} is not a versioned straycrab feature file", path);
+    };
+    let version = header.version;
+
+    if header.kind != Kind::Database {
+        let key = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let features = Features::from_file(&path.to_path_buf())?;
+        let (analysis, source) = (header.analysis, header.source);
+        let entry = DatabaseEntry { analysis, source, features };
+
+        return Ok(Report { path: path.to_path_buf(), kind: Kind::Features, version, entries: vec![EntryReport::new(key, &entry)] });
+    }
+
+    let db = FeatureDatabase::from_file(&path.to_path_buf())?;
+    let mut entries = vec![];
+    for key in db.keys() {
+        if let Some(entry) = db.entry(key)? {
+            entries.push(EntryReport::new(key.to_string(), &entry));
+        }
+    }
+
+    Ok(Report { path: path.to_path_buf(), kind: Kind::Database, version, entries })
+}
+
+impl EntryReport {
+    fn new(key: String, entry: &DatabaseEntry) -> EntryReport {
+        let features = &entry.features;
+        let frames = features.f0.len();
+        let voiced = features.f0.iter().copied().filter(|f| *f > 0.0).collect::<Vec<f64>>();
+
+        let mut issues = vec![];
+        if voiced.is_empty() {
+            issues.push(Issue::AllUnvoiced);
+        }
+        if !(F0_FLOOR..=F0_CEIL).contains(&features.base) {
+            issues.push(Issue::BaseOutOfRange);
+        }
+        if features.mgc.iter().flatten().any(|v| !v.is_finite()) {
+            issues.push(Issue::NonFiniteMgc);
+        }
+
+        EntryReport {
+            key,
+            frames,
+            duration: frames as f64 * features.frame_period / 1000.0,
+            sample_rate: features.sample_rate,
+            frame_period: features.frame_period,
+            base: features.base,
+            voiced_ratio: voiced.len() as f64 / frames.max(1) as f64,
+            f0_min: voiced.iter().copied().reduce(f64::min),
+            f0_max: voiced.iter().copied().reduce(f64::max),
+            mgc_dims: features.mgc.first().map_or(0, |row| row.len()),
+            bap_dims: features.bap.first().map_or(0, |row| row.len()),
+            analysis: entry.analysis.as_ref().map(AnalysisSummary::from),
+            source: entry.source.as_ref().map(SourceSummary::from),
+            issues,
+        }
+    }
+}
+
+impl From<&AnalysisParams> for AnalysisSummary {
+    fn from(analysis: &AnalysisParams) -> AnalysisSummary {
+        let f0_source = match &analysis.f0_source {
+            F0Source::Harvest => "harvest".to_string(),
+            F0Source::Dio => "dio".to_string(),
+            F0Source::Frq(sha256) => format!("frq:{}", hex(sha256)),
+        };
+        let base_source = match &analysis.base_source {
+            BaseSource::Estimated => "estimated".to_string(),
+            BaseSource::Folder(note) => format!("folder:{}", note),
+            BaseSource::PrefixMap(note) => format!("prefix_map:{}", note),
+            BaseSource::Override(note) => format!("override:{}", note),
+        };
+
+        AnalysisSummary {
+            frame_period: analysis.frame_period,
+            sample_rate: analysis.sample_rate,
+            fft_size: analysis.fft_size,
+            mgc_dims: analysis.mgc_dims,
+            d4c_threshold: analysis.d4c_threshold,
+            f0_floor: analysis.f0_floor,
+            f0_ceil: analysis.f0_ceil,
+            channel: analysis.channel,
+            f0_source,
+            f0_postprocess: analysis.f0_postprocess.clone(),
+            base_source,
+        }
+    }
+}
+
+impl From<&SourceInfo> for SourceSummary {
+    fn from(source: &SourceInfo) -> SourceSummary {
+        SourceSummary { size: source.size, modified: source.modified, sha256: hex(&source.sha256) }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Features => "sample features",
+            Kind::Database => "voicebank database",
+        };
+        writeln!(f, "{}: {}, format {}, {} entries", self.path.display(), kind, self.version, self.entries.len())?;
+        for entry in &self.entries {
+            write!(f, "{}", entry)?;
+        }
+
+        let flagged = self.entries.iter().filter(|e| !e.issues.is_empty()).count();
+        write!(f, "{} entries with issues", flagged)
+    }
+}
+
+impl fmt::Display for EntryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{}  {} frames  {:.2} s  base {:.1} Hz  voiced {:.0}%",
+            self.key, self.frames, self.duration, self.base, self.voiced_ratio * 100.0,
+        )?;
+        if let (Some(min), Some(max)) = (self.f0_min, self.f0_max) {
+            write!(f, "  F0 {:.1}-{:.1} Hz", min, max)?;
+        }
+        writeln!(f)?;
+
+        match &self.analysis {
+            Some(a) => writeln!(
+                f, "    {} Hz, {} ms, fft {}, {} mgc, d4c {}, F0 {}, base {}",
+                a.sample_rate, a.frame_period, a.fft_size, a.mgc_dims, a.d4c_threshold, a.f0_source, a.base_source,
+            )?,
+            None => writeln!(f, "    {} Hz, {} ms, {} mgc, analysis parameters not recorded", self.sample_rate, self.frame_period, self.mgc_dims)?,
+        }
+        for issue in &self.issues {
+            writeln!(f, "    ! {}", issue)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::io::scx::{CacheConfig, Header};
+    use crate::util::testing::temp;
+
+    fn features(f0: Vec<f64>, base: f64) -> Features {
+        let frames = f0.len();
+        Features::new(base, 5.0, 44100, 2048, f0, vec![vec![-9.0, 0.5]; frames], vec![vec![-1.0]; frames])
+    }
+
+    #[test]
+    fn test_inspect_features() {
+        let path = temp("inspect.scx");
+        let source = SourceInfo { size: 10, modified: 20, sha256: [0xab; 32] };
+        let header = Header::new(Kind::Features, None, Some(source));
+        features(vec![0.0, 210.0, 230.0, 220.0], 220.0).to_file(&path, &header, &CacheConfig::default()).unwrap();
+
+        let report = inspect(&path).unwrap();
+        assert_eq!((report.kind, report.version, report.entries.len()), (Kind::Features, scx::FORMAT_VERSION, 1));
+        let entry = &report.entries[0];
+        assert_eq!((entry.key.as_str(), entry.frames, entry.duration), ("inspect.scx", 4, 0.02));
+        assert_eq!((entry.voiced_ratio, entry.f0_min, entry.f0_max), (0.75, Some(210.0), Some(230.0)));
+        assert_eq!((entry.mgc_dims, entry.bap_dims), (2, 1));
+        assert!(entry.source.as_ref().unwrap().sha256.starts_with("abab"));
+        assert!(entry.issues.is_empty());
+    }
+
+    #[test]
+    fn test_inspect_database() {
+        let mut broken = features(vec![0.0; 3], 0.0);
+        broken.mgc[1][0] = f64::NAN;
+        let entries = HashMap::from([
+            ("C4/a.wav".to_string(), features(vec![220.0; 3], 220.0)),
+            ("C4/b.wav".to_string(), broken),
+        ]);
+        let path = temp("inspect_db.scx");
+        FeatureDatabase::new(entries).to_file(&path, &CacheConfig::default()).unwrap();
+
+        let report = inspect(&path).unwrap();
+        assert_eq!(report.kind, Kind::Database);
+        assert_eq!(report.entries.iter().map(|e| e.key.as_str()).collect::<Vec<&str>>(), ["C4/a.wav", "C4/b.wav"]);
+        assert_eq!(report.entries[1].issues, [Issue::AllUnvoiced, Issue::BaseOutOfRange, Issue::NonFiniteMgc]);
+
+        let text = report.to_string();
+        assert!(text.contains("voicebank database") && text.contains("! NaN or infinite mgc"), "{}", text);
+        assert!(text.ends_with("1 entries with issues"));
+
+        let json: serde_json::Value = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["kind"], "database");
+        assert_eq!(json["entries"][1]["issues"], serde_json::json!(["all_unvoiced", "base_out_of_range", "non_finite_mgc"]));
+        assert_eq!(json["entries"][0]["f0_max"], 220.0);
+        assert_eq!(json["entries"][1]["f0_min"], serde_json::Value::Null);
+    }
+}
\ No newline at end of file
@@ -1,43 +1,303 @@
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use rand::Rng;
+use serde::Deserialize;
 
-pub fn read_wav(file: &Path) -> Result<(Vec<f32>, u32)> {
+use crate::util::misc::DEFAULT_FS;
+
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[serde(default)]
+pub struct InputFormat {
+    /// Zero-based channel to analyze in multichannel samples; all channels are averaged when unset
+    pub channel: Option<u16>,
+    /// Rate samples are converted to before analysis; samples are analyzed at their own rate when unset
+    pub sample_rate: Option<u32>,
+}
+
+impl InputFormat {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(rate) = self.sample_rate {
+            if !(8000..=192000).contains(&rate) {
+                bail!("Unsupported analysis sample rate {}", rate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct OutputFormat {
+    /// 16 or 24 for integer PCM, 32 for float
+    pub bit_depth: u16,
+    pub sample_rate: u32,
+    /// Apply TPDF dither when writing integer PCM
+    pub dither: bool,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat {
+            bit_depth: 32,
+            sample_rate: DEFAULT_FS as u32,
+            dither: true,
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn validate(&self) -> Result<()> {
+        if ![16, 24, 32].contains(&self.bit_depth) {
+            bail!("Unsupported output bit depth {}, use 16, 24 or 32", self.bit_depth);
+        }
+
+        if !(8000..=192000).contains(&self.sample_rate) {
+            bail!("Unsupported output sample rate {}", self.sample_rate);
+        }
+
+        Ok(())
+    }
+}
+
+/// Read a wav file as mono samples in [-1, 1]. Integer formats are scaled by their bit depth.
+/// Multichannel files are averaged, unless `channel` selects a single (zero-based) channel.
+pub fn read_wav(file: &Path, channel: Option<u16>) -> Result<(Vec<f32>, u32)> {
     let mut reader = hound::WavReader::open(file)?;
     let spec = reader.spec();
 
-    match spec.sample_format {
+    let samples: Vec<f32> = match spec.sample_format {
         hound::SampleFormat::Int => {
-            let samples = reader.samples::<i32>().map(|s| s.unwrap() as f32).collect();
-            Ok((samples, spec.sample_rate))
+            // hound returns 8-bit samples as signed, so every depth is scaled the same way
+            let scale = 1.0 / (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.map(|s| s as f32 * scale)).collect::<Result<_, _>>()?
         }
         hound::SampleFormat::Float => {
-            let samples = reader.samples::<f32>().map(|s| s.unwrap()).collect();
-            Ok((samples, spec.sample_rate))
+            reader.samples::<f32>().collect::<Result<_, _>>()?
         }
+    };
+
+    let channels = spec.channels as usize;
+    if channels == 1 {
+        return Ok((samples, spec.sample_rate));
     }
+
+    let samples = match channel {
+        Some(c) if (c as usize) < channels => {
+            samples.chunks_exact(channels).map(|frame| frame[c as usize]).collect()
+        }
+        Some(c) => bail!("Channel {} requested, but {:?} has {} channels", c, file, channels),
+        None => {
+            samples.chunks_exact(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect()
+        }
+    };
+
+    Ok((samples, spec.sample_rate))
 }
 
-pub fn write_wav(file: &Path, samples: Vec<f32>, sample_rate: u32) -> Result<()> {
+/// Write mono samples in `format`. The samples must already be at `format.sample_rate`.
+pub fn write_wav(file: &Path, samples: Vec<f32>, format: &OutputFormat) -> Result<()> {
+    format.validate()?;
     std::fs::create_dir_all(file.parent().unwrap())?;
 
     let spec = hound::WavSpec {
         channels: 1,
-        sample_rate,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Float,
+        sample_rate: format.sample_rate,
+        bits_per_sample: format.bit_depth,
+        sample_format: if format.bit_depth == 32 { hound::SampleFormat::Float } else { hound::SampleFormat::Int },
     };
     let mut writer = hound::WavWriter::create(file, spec)?;
-    for sample in samples {
-        writer.write_sample(sample)?;
+
+    if format.bit_depth == 32 {
+        for sample in samples {
+            writer.write_sample(sample)?;
+        }
+    } else {
+        for sample in quantize(&samples, format.bit_depth, format.dither) {
+            writer.write_sample(sample)?;
+        }
     }
+
     writer.finalize()?;
     Ok(())
 }
 
+/// A named position in a rendered file, written as a cue point with a label
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cue {
+    /// Sample frame the cue points at
+    pub position: u32,
+    pub label: String,
+}
+
+/// Chunks written after the audio data: `cue ` with a `LIST/adtl` of labels, and `LIST/INFO`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct WavMetadata {
+    pub cues: Vec<Cue>,
+    /// INFO entries such as `ISFT` (software), `INAM` (name) and `ICMT` (comment)
+    pub info: Vec<([u8; 4], String)>,
+}
+
+impl std::fmt::Display for WavMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (id, text) in &self.info {
+            writeln!(f, "{}  {}", String::from_utf8_lossy(id), text)?;
+        }
+        for cue in &self.cues {
+            writeln!(f, "{:>10}  {}", cue.position, cue.label)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Append `metadata` to a wav file written by `write_wav`, and fix up the RIFF size
+pub fn write_metadata(file: &Path, metadata: &WavMetadata) -> Result<()> {
+    let mut chunks = vec![];
+
+    if !metadata.cues.is_empty() {
+        let mut cue = (metadata.cues.len() as u32).to_le_bytes().to_vec();
+        let mut adtl = b"adtl".to_vec();
+
+        for (i, c) in metadata.cues.iter().enumerate() {
+            let id = (i as u32 + 1).to_le_bytes();
+
+            // ID, play order position, data chunk ID, chunk start, block start, sample offset
+            cue.extend(id);
+            cue.extend(c.position.to_le_bytes());
+            cue.extend(b"data");
+            cue.extend(0u32.to_le_bytes());
+            cue.extend(0u32.to_le_bytes());
+            cue.extend(c.position.to_le_bytes());
+
+            let mut labl = id.to_vec();
+            labl.extend(c.label.as_bytes());
+            labl.push(0);
+            push_chunk(&mut adtl, b"labl", &labl);
+        }
+
+        push_chunk(&mut chunks, b"cue ", &cue);
+        push_chunk(&mut chunks, b"LIST", &adtl);
+    }
+
+    if !metadata.info.is_empty() {
+        let mut info = b"INFO".to_vec();
+        for (id, text) in &metadata.info {
+            let mut data = text.as_bytes().to_vec();
+            data.push(0);
+            push_chunk(&mut info, id, &data);
+        }
+
+        push_chunk(&mut chunks, b"LIST", &info);
+    }
+
+    let mut f = OpenOptions::new().read(true).write(true).open(file)?;
+    // Chunks start on even offsets, so pad an odd-sized data chunk first
+    if f.seek(SeekFrom::End(0))? % 2 == 1 {
+        f.write_all(&[0])?;
+    }
+    f.write_all(&chunks)?;
+
+    let riff_size = f.seek(SeekFrom::End(0))? - 8;
+    f.seek(SeekFrom::Start(4))?;
+    f.write_all(&(riff_size as u32).to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Read the cues, labels and INFO entries of a wav file
+pub fn read_metadata(file: &Path) -> Result<WavMetadata> {
+    let data = std::fs::read(file)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        bail!("{:?} is not a wav file", file);
+    }
+
+    let mut positions = vec![];
+    let mut labels = std::collections::HashMap::new();
+    let mut info = vec![];
+
+    for (id, body) in chunks(&data[12..]) {
+        match id {
+            b"cue " if body.len() >= 4 => {
+                for point in body[4..].chunks_exact(24) {
+                    positions.push((u32_at(point, 0), u32_at(point, 20)));
+                }
+            }
+            b"LIST" if body.len() >= 4 => {
+                for (sub, text) in chunks(&body[4..]) {
+                    match &body[0..4] {
+                        b"adtl" if sub == b"labl" && text.len() >= 4 => {
+                            labels.insert(u32_at(text, 0), c_string(&text[4..]));
+                        }
+                        b"INFO" => info.push((*sub, c_string(text))),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let cues = positions.into_iter().map(|(id, position)| Cue {
+        position,
+        label: labels.remove(&id).unwrap_or_default(),
+    }).collect();
+
+    Ok(WavMetadata { cues, info })
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend(id);
+    out.extend((data.len() as u32).to_le_bytes());
+    out.extend(data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+/// Iterate over the `(id, body)` pairs of a sequence of RIFF chunks, stopping at the first truncated one
+fn chunks(mut data: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if data.len() < 8 {
+            return None;
+        }
+
+        let id: &[u8; 4] = data[0..4].try_into().unwrap();
+        let size = u32_at(data, 4) as usize;
+        let body = data.get(8..8 + size)?;
+        data = data.get(8 + size + size % 2..).unwrap_or(&[]);
+
+        Some((id, body))
+    })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// Scale samples in [-1, 1] to signed integers of `bits` bits, optionally with TPDF dither of +-1 LSB
+pub fn quantize(samples: &[f32], bits: u16, dither: bool) -> Vec<i32> {
+    let max = (1i64 << (bits - 1)) as f64;
+    let mut rng = rand::thread_rng();
+
+    samples.iter().map(|s| {
+        let noise = if dither { rng.gen::<f64>() - rng.gen::<f64>() } else { 0.0 };
+        (*s as f64 * max + noise).round().clamp(-max, max - 1.0) as i32
+    }).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::util::testing::temp;
     use dotenv::dotenv;
     use std::{env, path::PathBuf};
 
@@ -47,18 +307,136 @@ mod tests {
         let samples = vec![0.0; 44100];
         let sample_rate = 44100;
 
-        write_wav(path, samples.clone(), sample_rate).unwrap();
-        let (read_samples, read_sample_rate) = read_wav(path).unwrap();
+        write_wav(path, samples.clone(), &OutputFormat::default()).unwrap();
+        let (read_samples, read_sample_rate) = read_wav(path, None).unwrap();
 
         assert_eq!(samples, read_samples);
         assert_eq!(sample_rate, read_sample_rate);
     }
 
+    fn write_fixture(name: &str, spec: hound::WavSpec, frames: &[Vec<f32>]) -> PathBuf {
+        let path = temp(name);
+
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for frame in frames {
+            for s in frame {
+                match spec.sample_format {
+                    hound::SampleFormat::Float => writer.write_sample(*s).unwrap(),
+                    hound::SampleFormat::Int => {
+                        let max = (1i64 << (spec.bits_per_sample - 1)) as f32;
+                        let v = (*s * max).round().clamp(-max, max - 1.0) as i32;
+                        match spec.bits_per_sample {
+                            8 => writer.write_sample(v as i8).unwrap(),
+                            16 => writer.write_sample(v as i16).unwrap(),
+                            _ => writer.write_sample(v).unwrap(),
+                        }
+                    }
+                }
+            }
+        }
+        writer.finalize().unwrap();
+
+        path
+    }
+
+    #[test]
+    fn test_read_bit_depths() {
+        let frames = [0.5, -0.25, 0.0, -1.0].iter().map(|s| vec![*s]).collect::<Vec<Vec<f32>>>();
+
+        for (bits, format) in [(8, hound::SampleFormat::Int), (16, hound::SampleFormat::Int), (24, hound::SampleFormat::Int), (32, hound::SampleFormat::Int), (32, hound::SampleFormat::Float)] {
+            let spec = hound::WavSpec { channels: 1, sample_rate: 22050, bits_per_sample: bits, sample_format: format };
+            let path = write_fixture(&format!("depth_{}_{:?}.wav", bits, format), spec, &frames);
+
+            let (samples, sample_rate) = read_wav(&path, None).unwrap();
+            assert_eq!(sample_rate, 22050);
+            assert_eq!(samples, vec![0.5, -0.25, 0.0, -1.0], "{} bit {:?}", bits, format);
+        }
+    }
+
+    #[test]
+    fn test_read_stereo() {
+        let spec = hound::WavSpec { channels: 2, sample_rate: 48000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let path = write_fixture("stereo.wav", spec, &[vec![0.5, 0.25], vec![-0.5, 0.0]]);
+
+        assert_eq!(read_wav(&path, None).unwrap(), (vec![0.375, -0.25], 48000));
+        assert_eq!(read_wav(&path, Some(0)).unwrap().0, vec![0.5, -0.5]);
+        assert_eq!(read_wav(&path, Some(1)).unwrap().0, vec![0.25, 0.0]);
+        assert!(read_wav(&path, Some(2)).is_err());
+    }
+
+    #[test]
+    fn test_write_pcm() {
+        let path = temp("pcm.wav");
+        let samples = (0..4800).map(|i| (i as f32 / 10.0).sin() * 0.5).collect::<Vec<f32>>();
+
+        for bit_depth in [16, 24] {
+            let format = OutputFormat { bit_depth, sample_rate: 48000, dither: false };
+            write_wav(&path, samples.clone(), &format).unwrap();
+
+            let mut reader = hound::WavReader::open(&path).unwrap();
+            assert_eq!(reader.spec().bits_per_sample, bit_depth);
+            assert_eq!(reader.spec().sample_format, hound::SampleFormat::Int);
+            assert_eq!(reader.spec().sample_rate, 48000);
+
+            let scale = (1i64 << (bit_depth - 1)) as f32;
+            for (a, b) in reader.samples::<i32>().zip(samples.iter()) {
+                assert!((a.unwrap() as f32 / scale - b).abs() <= 1.0 / scale);
+            }
+        }
+    }
+
+    #[test]
+    fn test_tpdf_dither() {
+        let samples = vec![0.25; 10000];
+        let dithered = quantize(&samples, 16, true);
+        let target = 0.25 * 32768.0;
+
+        // Triangular noise stays within one LSB and averages out
+        assert!(dithered.iter().all(|s| (*s as f64 - target).abs() <= 1.0));
+        assert!(dithered.iter().any(|s| *s as f64 != target));
+        let mean = dithered.iter().map(|s| *s as f64).sum::<f64>() / dithered.len() as f64;
+        assert!((mean - target).abs() < 0.05);
+
+        // Full scale is clipped rather than wrapped
+        assert_eq!(quantize(&[1.0, -1.0], 16, false), vec![32767, -32768]);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let path = temp("metadata.wav");
+        let metadata = WavMetadata {
+            cues: vec![
+                Cue { position: 4410, label: "Consonant end".to_string() },
+                Cue { position: 30000, label: "Stretch join 1".to_string() },
+            ],
+            info: vec![(*b"ISFT", "straycrab 0.1.0".to_string()), (*b"INAM", "_あ".to_string())],
+        };
+
+        // An odd number of 24-bit samples leaves the data chunk odd-sized
+        let samples = vec![0.25; 44101];
+        write_wav(&path, samples, &OutputFormat { bit_depth: 24, dither: false, ..Default::default() }).unwrap();
+        write_metadata(&path, &metadata).unwrap();
+
+        assert_eq!(read_metadata(&path).unwrap(), metadata);
+
+        // The audio is untouched and the RIFF size covers the new chunks
+        let (read, _) = read_wav(&path, None).unwrap();
+        assert_eq!(read.len(), 44101);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(u32_at(&data, 4) as usize, data.len() - 8);
+    }
+
+    #[test]
+    fn test_invalid_format() {
+        let format = OutputFormat { bit_depth: 8, ..Default::default() };
+        assert!(write_wav(Path::new("invalid.wav"), vec![], &format).is_err());
+    }
+
     #[test]
     fn test_read_wav() {
         dotenv().ok();
 
         let path = PathBuf::from(env::var("TEST_FILE").unwrap());
-        let (samples, sample_rate) = read_wav(&path).unwrap();
+        let (samples, sample_rate) = read_wav(&path, None).unwrap();
     }
 }
\ No newline at end of file
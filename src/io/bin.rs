@@ -1,11 +1,15 @@
-use std::{fs::File, io::{BufRead, BufReader, Read, Write}, path::PathBuf};
+use std::io::{BufReader, Read, Write};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
+use bincode::Options;
+use serde::de::DeserializeOwned;
 
-pub fn encode(data: &[u8], path: &PathBuf) -> Result<()> {
-    // Encode data to zstd compressed file
-    let file = File::create(path)?;
-    let mut encoder = zstd::Encoder::new(file, 0)?;
+/// zstd's own default, a good balance for feature files
+pub const DEFAULT_LEVEL: i32 = 3;
+
+pub fn encode<W: Write>(data: &[u8], writer: W, level: i32) -> Result<()> {
+    // Encode data to a zstd stream
+    let mut encoder = zstd::Encoder::new(writer, level)?;
 
     encoder.write_all(data)?;
     encoder.finish()?;
@@ -13,23 +17,39 @@ pub fn encode(data: &[u8], path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-pub fn decode(path: &PathBuf) -> Result<Vec<u8>> {
-    // Decode zstd compressed file
-    let mut file = File::open(path)?;
-    let decoder = zstd::Decoder::new(file)?;
-
-    let mut decoded: Vec<u8> = vec![];
-    let bytes = BufReader::new(decoder).bytes();
-    for byte in bytes {
-        decoded.push(byte?);
-    }
+/// Decode a whole zstd stream, which only tests need as everything else deserializes on the fly
+#[cfg(test)]
+pub fn decode<R: Read>(reader: R) -> Result<Vec<u8>> {
+    let mut decoded = vec![];
+    zstd::Decoder::new(reader)?.read_to_end(&mut decoded)?;
 
     Ok(decoded)
 }
 
+/// Deserialize bincode from `data`, decompressing on the fly when it is `compressed` as a zstd stream. All of
+/// `data` has to be consumed, so data in another layout fails instead of being misread.
+pub fn deserialize<T: DeserializeOwned>(data: &[u8], compressed: bool) -> Result<T> {
+    let options = bincode::DefaultOptions::new().with_fixint_encoding().reject_trailing_bytes();
+
+    if !compressed {
+        return Ok(options.deserialize(data)?);
+    }
+
+    // bincode reads a few bytes at a time, so buffer the decoder's output
+    let mut decoder = BufReader::with_capacity(1 << 16, zstd::Decoder::with_buffer(data)?);
+    let res = options.deserialize_from(&mut decoder)?;
+    if decoder.read(&mut [0])? != 0 {
+        bail!("Trailing data after the serialized value");
+    }
+
+    Ok(res)
+}
 
 #[cfg(test)]
 mod tests {
+    use std::fs::File;
+    use std::path::PathBuf;
+
     use crate::features::Features;
 
     use super::*;
@@ -45,11 +65,11 @@ mod tests {
         println!("Serialization took: {:?}", t0.elapsed());
 
         let t1 = std::time::Instant::now();
-        encode(&serialized, &path).unwrap();
+        encode(&serialized, File::create(&path).unwrap(), DEFAULT_LEVEL).unwrap();
         println!("Encoding took: {:?}", t1.elapsed());
 
         let t2 = std::time::Instant::now();
-        let decoded = decode(&path).unwrap();
+        let decoded = decode(File::open(&path).unwrap()).unwrap();
         println!("Decoding took: {:?}", t2.elapsed());
 
         assert_eq!(serialized, decoded.as_slice());
@@ -61,4 +81,74 @@ mod tests {
 
         assert_eq!(data, deserialized);
     }
+
+    #[test]
+    fn test_deserialize() {
+        let data = (1.5f64, vec![1u32, 2, 3]);
+        let serialized = bincode::serialize(&data).unwrap();
+        let mut compressed = vec![];
+        encode(&serialized, &mut compressed, 19).unwrap();
+
+        assert_eq!(deserialize::<(f64, Vec<u32>)>(&serialized, false).unwrap(), data);
+        assert_eq!(deserialize::<(f64, Vec<u32>)>(&compressed, true).unwrap(), data);
+
+        // Leftover bytes mean the data has a different layout
+        assert!(deserialize::<(f64, Vec<u32>, u8)>(&compressed, true).is_err());
+        assert!(deserialize::<(f64, Vec<u16>)>(&compressed, true).is_err());
+        assert!(deserialize::<f64>(&serialized, false).is_err());
+
+        // Compressed data is never mistaken for serialized data or the other way round
+        assert!(deserialize::<(f64, Vec<u32>)>(&compressed, false).is_err());
+        assert!(deserialize::<(f64, Vec<u32>)>(&serialized, true).is_err());
+    }
+
+    /// Run with `cargo test --release bench_decode -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_decode() {
+        // Five minutes of features at 5 ms
+        let frames = 60000;
+        let features = Features::new(
+            220.0, 5.0, 44100, 2048,
+            (0..frames).map(|i| 220.0 + (i as f64 * 0.01).sin()).collect(),
+            (0..frames).map(|i| (0..64).map(|k| ((i * k) as f64 * 0.001).cos()).collect()).collect(),
+            (0..frames).map(|i| (0..5).map(|k| ((i + k) as f64 * 0.01).sin()).collect()).collect(),
+        );
+        let serialized = bincode::serialize(&features).unwrap();
+        println!("Serialized size: {} MB", serialized.len() / 1_000_000);
+
+        for level in [1, DEFAULT_LEVEL, 9, 19] {
+            let mut compressed = vec![];
+            let t0 = std::time::Instant::now();
+            encode(&serialized, &mut compressed, level).unwrap();
+            println!("Level {}: {} MB, encoding took {:?}", level, compressed.len() / 1_000_000, t0.elapsed());
+        }
+
+        let mut compressed = vec![];
+        encode(&serialized, &mut compressed, DEFAULT_LEVEL).unwrap();
+
+        // The previous decoder, one byte at a time
+        let t0 = std::time::Instant::now();
+        let mut bytewise = vec![];
+        for byte in BufReader::new(zstd::Decoder::new(compressed.as_slice()).unwrap()).bytes() {
+            bytewise.push(byte.unwrap());
+        }
+        let old: Features = bincode::deserialize(&bytewise).unwrap();
+        let old_time = t0.elapsed();
+
+        let t1 = std::time::Instant::now();
+        let bulk: Features = bincode::deserialize(&decode(compressed.as_slice()).unwrap()).unwrap();
+        let bulk_time = t1.elapsed();
+
+        let t2 = std::time::Instant::now();
+        let streamed: Features = deserialize(&compressed, true).unwrap();
+        let streamed_time = t2.elapsed();
+
+        println!("Bytewise decode and deserialize: {:?}", old_time);
+        println!("Bulk decode and deserialize: {:?}", bulk_time);
+        println!("Streaming deserialize: {:?}", streamed_time);
+
+        assert!(old == features && bulk == features && streamed == features);
+        assert!(streamed_time < old_time);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,169 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+
+const MAGIC: &[u8; 8] = b"FREQ0003";
+/// Magic, samples per frame, average frequency, 16 reserved bytes and frame count
+const HEADER_LEN: usize = 40;
+/// Samples per frame written by UTAU's frequency tool
+pub const DEFAULT_HOP: i32 = 256;
+
+/// An UTAU frequency map (`<sample>_wav.frq`). Frame `i` describes the audio around sample `i * hop`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frq {
+    /// Samples per frame, at the sample's own rate
+    pub hop: i32,
+    /// Average frequency of the voiced frames in Hz
+    pub average: f64,
+    /// Frequency of each frame in Hz, 0 where unvoiced
+    pub f0: Vec<f64>,
+    /// Amplitude of each frame, in 16-bit sample units
+    pub amplitude: Vec<f64>,
+}
+
+impl Frq {
+    /// Where UTAU keeps the frequency map of `wav`: `a.wav` has `a_wav.frq`
+    pub fn path_for(wav: &Path) -> PathBuf {
+        let name = wav.file_name().unwrap_or_default().to_string_lossy().replace('.', "_");
+        wav.with_file_name(format!("{}.frq", name))
+    }
+
+    pub fn read(path: &Path) -> Result<Frq> {
+        let data = std::fs::read(path)?;
+        if !data.starts_with(MAGIC) {
+            bail!("{:?} is not a FREQ0003 frequency map", path);
+        }
+        if data.len() < HEADER_LEN {
+            bail!("{:?} is truncated", path);
+        }
+
+        let hop = i32::from_le_bytes(data[8..12].try_into().unwrap());
+        let average = f64::from_le_bytes(data[12..20].try_into().unwrap());
+        let frames = i32::from_le_bytes(data[36..40].try_into().unwrap()).max(0) as usize;
+        if hop <= 0 {
+            bail!("{:?} has {} samples per frame", path, hop);
+        }
+
+        let Some(body) = data.get(HEADER_LEN..HEADER_LEN + frames * 16) else {
+            bail!("{:?} is truncated", path);
+        };
+        let (f0, amplitude) = body.chunks_exact(16).map(|frame| (
+            f64::from_le_bytes(frame[..8].try_into().unwrap()),
+            f64::from_le_bytes(frame[8..].try_into().unwrap()),
+        )).unzip();
+
+        Ok(Frq { hop, average, f0, amplitude })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(MAGIC)?;
+        file.write_all(&self.hop.to_le_bytes())?;
+        file.write_all(&self.average.to_le_bytes())?;
+        file.write_all(&[0; 16])?;
+        file.write_all(&(self.f0.len() as i32).to_le_bytes())?;
+        for (f0, amplitude) in self.f0.iter().zip(&self.amplitude) {
+            file.write_all(&f0.to_le_bytes())?;
+            file.write_all(&amplitude.to_le_bytes())?;
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+
+    /// Frequency map of `samples` at `sample_rate`, whose F0 was analyzed every `frame_period` ms
+    pub fn from_f0(f0: &[f64], frame_period: f64, samples: &[f32], sample_rate: u32) -> Frq {
+        let hop = DEFAULT_HOP as usize;
+        let frames = samples.len().div_ceil(hop);
+
+        let f0 = (0..frames).map(|i| {
+            let t = (i * hop) as f64 / sample_rate as f64;
+            contour_at(f0, t * 1000.0 / frame_period)
+        }).collect::<Vec<f64>>();
+        let amplitude = (0..frames).map(|i| {
+            let frame = &samples[i * hop..((i + 1) * hop).min(samples.len())];
+            (frame.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / frame.len() as f64).sqrt() * 32768.0
+        }).collect();
+
+        let voiced = f0.iter().filter(|f| **f > 0.0).collect::<Vec<&f64>>();
+        let average = match voiced.len() {
+            0 => 0.0,
+            n => voiced.into_iter().sum::<f64>() / n as f64,
+        };
+
+        Frq { hop: DEFAULT_HOP, average, f0, amplitude }
+    }
+
+    /// F0 at each of `times` in seconds, for a sample at `sample_rate`
+    pub fn f0_at(&self, times: &[f64], sample_rate: u32) -> Vec<f64> {
+        times.iter().map(|t| contour_at(&self.f0, t * sample_rate as f64 / self.hop as f64)).collect()
+    }
+}
+
+/// `f0` at fractional frame `pos`, interpolated between voiced frames and otherwise taken from the nearest
+fn contour_at(f0: &[f64], pos: f64) -> f64 {
+    let Some(last) = f0.len().checked_sub(1) else {
+        return 0.0;
+    };
+    let i = (pos.max(0.0).floor() as usize).min(last);
+    let (a, b) = (f0[i], f0[(i + 1).min(last)]);
+    let frac = (pos - i as f64).clamp(0.0, 1.0);
+
+    if a > 0.0 && b > 0.0 {
+        a + (b - a) * frac
+    } else if frac < 0.5 {
+        a
+    } else {
+        b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp;
+
+    #[test]
+    fn test_path_for() {
+        assert_eq!(Frq::path_for(Path::new("voice/C4/_あ.wav")), PathBuf::from("voice/C4/_あ_wav.frq"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frq = Frq { hop: 256, average: 221.0, f0: vec![0.0, 220.0, 222.0], amplitude: vec![10.0, 2000.0, 1800.5] };
+        let path = temp("round_trip_wav.frq");
+        frq.write(&path).unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), HEADER_LEN + 3 * 16);
+        assert_eq!(&data[..8], b"FREQ0003");
+        assert_eq!(Frq::read(&path).unwrap(), frq);
+
+        std::fs::write(&path, &data[..HEADER_LEN + 20]).unwrap();
+        assert!(Frq::read(&path).is_err());
+    }
+
+    #[test]
+    fn test_contour() {
+        // 100 ms at 5 ms per frame, voiced from 20 ms on and gliding up
+        let f0 = (0..21).map(|i| if i < 4 { 0.0 } else { 200.0 + i as f64 }).collect::<Vec<f64>>();
+        let samples = vec![0.5; 4410];
+        let frq = Frq::from_f0(&f0, 5.0, &samples, 44100);
+
+        assert_eq!(frq.f0.len(), 18);
+        assert_eq!(frq.f0[0], 0.0);
+        assert!((frq.amplitude[0] - 16384.0).abs() < 1e-6);
+        assert!(frq.average > 200.0);
+
+        // Back on the 5 ms grid, voicing and pitch survive
+        let times = (0..20).map(|i| i as f64 * 0.005).collect::<Vec<f64>>();
+        let back = frq.f0_at(&times, 44100);
+        assert_eq!(back[0], 0.0);
+        for i in 5..19 {
+            assert!((back[i] - f0[i]).abs() < 0.5, "frame {}: {} vs {}", i, back[i], f0[i]);
+        }
+    }
+}
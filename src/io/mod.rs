@@ -1,3 +1,10 @@
 pub mod audio;
 pub mod ust;
-pub mod bin;
\ No newline at end of file
+pub mod bin;
+pub mod scx;
+pub mod npz;
+pub mod frq;
+pub mod quant;
+pub mod text;
+pub mod prefix_map;
+pub mod oto;
\ No newline at end of file
@@ -0,0 +1,156 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use npyz::npz::{NpzArchive, NpzWriter};
+use npyz::WriterBuilder;
+
+use crate::features::Features;
+
+/// straycat analyzes everything at 44.1 kHz with a 5 ms frame period and CheapTrick's default FFT size for
+/// that rate, and stores none of them
+pub const STRAYCAT_FS: i32 = 44100;
+pub const STRAYCAT_FRAME_PERIOD: f64 = 5.0;
+pub const STRAYCAT_FFT_SIZE: i32 = 2048;
+
+type Writer = NpzWriter<BufWriter<File>>;
+type Reader = NpzArchive<BufReader<File>>;
+
+/// Write `features` as NumPy arrays, under the names straycat uses (`base`, `f0`, `mgc` and `bap`) plus
+/// `frame_period`, `sample_rate` and `fft_size`
+pub fn write(path: &Path, features: &Features) -> Result<()> {
+    let mut npz = NpzWriter::create(path)?;
+
+    write_array(&mut npz, "base", &[], [features.base])?;
+    write_array(&mut npz, "frame_period", &[], [features.frame_period])?;
+    write_array(&mut npz, "sample_rate", &[], [features.sample_rate as i64])?;
+    write_array(&mut npz, "fft_size", &[], [features.fft_size as i64])?;
+    write_array(&mut npz, "f0", &[features.f0.len() as u64], features.f0.iter().copied())?;
+    write_matrix(&mut npz, "mgc", &features.mgc)?;
+    write_matrix(&mut npz, "bap", &features.bap)?;
+
+    Ok(())
+}
+
+/// Read features written by `write` or cached by straycat as `.sc.npz`. Files without `frame_period`,
+/// `sample_rate` and `fft_size` are taken to be straycat's.
+pub fn read(path: &Path) -> Result<Features> {
+    let mut npz = NpzArchive::open(path)?;
+
+    let base = read_array(&mut npz, "base", path)?.0;
+    let f0 = read_array(&mut npz, "f0", path)?.0;
+    let mgc = read_matrix(&mut npz, "mgc", path)?;
+    let bap = read_matrix(&mut npz, "bap", path)?;
+
+    if base.len() != 1 {
+        bail!("{:?}: base must be a single value", path);
+    }
+    if mgc.len() != f0.len() || bap.len() != f0.len() {
+        bail!("{:?}: f0, mgc and bap have {}, {} and {} frames", path, f0.len(), mgc.len(), bap.len());
+    }
+
+    let has_frame_period = npz.array_names().any(|n| n == "frame_period");
+    let frame_period = match has_frame_period {
+        true => read_array(&mut npz, "frame_period", path)?.0[0],
+        false => STRAYCAT_FRAME_PERIOD,
+    };
+    let sample_rate = match npz.by_name("sample_rate")? {
+        Some(array) => array.into_vec::<i64>()?[0] as i32,
+        None => STRAYCAT_FS,
+    };
+    let fft_size = match npz.by_name("fft_size")? {
+        Some(array) => array.into_vec::<i64>()?[0] as i32,
+        None => STRAYCAT_FFT_SIZE,
+    };
+
+    Ok(Features::new(base[0], frame_period, sample_rate, fft_size, f0, mgc, bap))
+}
+
+fn write_array<T, I>(npz: &mut Writer, name: &str, shape: &[u64], data: I) -> Result<()>
+where
+    T: npyz::AutoSerialize,
+    I: IntoIterator<Item = T>,
+{
+    let mut writer = npz.array::<T>(name, Default::default())?.default_dtype().shape(shape).begin_nd()?;
+    writer.extend(data)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+fn write_matrix(npz: &mut Writer, name: &str, rows: &[Vec<f64>]) -> Result<()> {
+    let dims = rows.first().map_or(0, |row| row.len());
+    if rows.iter().any(|row| row.len() != dims) {
+        bail!("Rows of {} differ in length", name);
+    }
+
+    write_array(npz, name, &[rows.len() as u64, dims as u64], rows.iter().flatten().copied())
+}
+
+/// A float array of either precision as `f64`, with its shape
+fn read_array(npz: &mut Reader, name: &str, path: &Path) -> Result<(Vec<f64>, Vec<u64>)> {
+    let array = npz.by_name(name)?.ok_or_else(|| anyhow!("{:?} has no {} array", path, name))?;
+    let shape = array.shape().to_vec();
+
+    let data = match array.dtype().descr().as_str() {
+        "'<f8'" => array.into_vec::<f64>()?,
+        "'<f4'" => array.into_vec::<f32>()?.into_iter().map(|v| v as f64).collect(),
+        dtype => bail!("{:?}: {} has unsupported type {}", path, name, dtype),
+    };
+
+    Ok((data, shape))
+}
+
+fn read_matrix(npz: &mut Reader, name: &str, path: &Path) -> Result<Vec<Vec<f64>>> {
+    let (data, shape) = read_array(npz, name, path)?;
+    let [_, dims] = shape[..] else {
+        bail!("{:?}: {} must be two-dimensional", path, name);
+    };
+
+    Ok(data.chunks(dims.max(1) as usize).map(|row| row.to_vec()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp;
+
+    #[test]
+    fn test_round_trip() {
+        let features = Features::new(261.6, 2.0, 48000, 4096, vec![0.0, 260.0, 262.5], vec![vec![-3.0, 0.5]; 3], vec![vec![-0.1]; 3]);
+        let path = temp("round_trip.npz");
+
+        write(&path, &features).unwrap();
+        assert_eq!(read(&path).unwrap(), features);
+    }
+
+    #[test]
+    fn test_read_straycat() {
+        // What `np.savez_compressed(path, base=base_f0, f0=f0, mgc=mgc, bap=bap)` writes, with float32 bap
+        let path = temp("straycat.sc.npz");
+        {
+            let mut npz = NpzWriter::create(&path).unwrap();
+            write_array(&mut npz, "base", &[], [220.0f64]).unwrap();
+            write_array(&mut npz, "f0", &[2], [219.0f64, 221.0]).unwrap();
+            write_array(&mut npz, "mgc", &[2, 3], [1.0f64, 2.0, 3.0, 4.0, 5.0, 6.0]).unwrap();
+            write_array(&mut npz, "bap", &[2, 1], [-0.5f32, -0.25]).unwrap();
+        }
+
+        let features = read(&path).unwrap();
+        assert_eq!(features, Features::new(
+            220.0, STRAYCAT_FRAME_PERIOD, STRAYCAT_FS, STRAYCAT_FFT_SIZE,
+            vec![219.0, 221.0], vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]], vec![vec![-0.5], vec![-0.25]],
+        ));
+
+        // Arrays of different lengths are rejected
+        {
+            let mut npz = NpzWriter::create(&path).unwrap();
+            write_array(&mut npz, "base", &[], [220.0f64]).unwrap();
+            write_array(&mut npz, "f0", &[3], [219.0f64, 221.0, 0.0]).unwrap();
+            write_array(&mut npz, "mgc", &[2, 1], [1.0f64, 2.0]).unwrap();
+            write_array(&mut npz, "bap", &[2, 1], [1.0f64, 2.0]).unwrap();
+        }
+        assert!(read(&path).unwrap_err().to_string().contains("frames"));
+    }
+}
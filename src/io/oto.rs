@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+use crate::io::text;
+
+/// A line of an `oto.ini`: where an alias lies in its sample, with times in ms
+#[derive(Debug, Clone, PartialEq)]
+pub struct OtoEntry {
+    /// Sample path relative to the voicebank root
+    pub file: PathBuf,
+    pub alias: String,
+    pub offset: f64,
+    pub consonant: f64,
+    /// Negative values count from the offset, others from the end of the sample
+    pub cutoff: f64,
+    pub preutterance: f64,
+    pub overlap: f64,
+}
+
+/// The aliases of a voicebank, from the `oto.ini` in its root and in each of its folders
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Oto {
+    pub entries: Vec<OtoEntry>,
+}
+
+impl Oto {
+    pub fn read(voicebank: &Path) -> Result<Oto> {
+        let mut oto = Oto::default();
+        oto.read_dir(voicebank, Path::new(""))?;
+
+        if oto.entries.is_empty() {
+            return Err(anyhow!("{:?} has no oto.ini entries", voicebank));
+        }
+        Ok(oto)
+    }
+
+    fn read_dir(&mut self, dir: &Path, relative: &Path) -> Result<()> {
+        let ini = dir.join("oto.ini");
+        if ini.is_file() {
+            self.entries.extend(Self::parse(&text::decode(&std::fs::read(&ini)?), relative));
+        }
+
+        let mut folders = std::fs::read_dir(dir)?.map(|e| Ok(e?.path())).collect::<Result<Vec<PathBuf>>>()?;
+        folders.sort();
+        for folder in folders.into_iter().filter(|p| p.is_dir()) {
+            let name = folder.file_name().unwrap().to_owned();
+            self.read_dir(&folder, &relative.join(name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Parse `<file>=<alias>,<offset>,<consonant>,<cutoff>,<preutterance>,<overlap>` lines of an `oto.ini` in
+    /// the folder `relative`. Samples without an alias go by their file name without extension, and empty
+    /// values are 0.
+    pub fn parse(text: &str, relative: &Path) -> Vec<OtoEntry> {
+        text.lines().filter_map(|line| {
+            let (file, values) = line.trim().split_once('=')?;
+            let mut fields = values.split(',');
+            let alias = fields.next()?.to_string();
+            let numbers = fields.map(|v| v.trim().parse::<f64>().unwrap_or(0.0)).chain(std::iter::repeat(0.0)).take(5).collect::<Vec<f64>>();
+            let alias = match alias.is_empty() {
+                true => Path::new(file).file_stem()?.to_string_lossy().into_owned(),
+                false => alias,
+            };
+
+            Some(OtoEntry {
+                file: relative.join(file),
+                alias,
+                offset: numbers[0],
+                consonant: numbers[1],
+                cutoff: numbers[2],
+                preutterance: numbers[3],
+                overlap: numbers[4],
+            })
+        }).collect()
+    }
+
+    /// The first entry with `alias`
+    pub fn get(&self, alias: &str) -> Option<&OtoEntry> {
+        self.entries.iter().find(|e| e.alias == alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp;
+
+    #[test]
+    fn test_parse() {
+        let entries = Oto::parse("_あ.wav=- あ,100,150,-400,80,30\r\n_い.wav=,50.5,,200\r\nbroken\r\n", Path::new("C4"));
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], OtoEntry {
+            file: PathBuf::from("C4/_あ.wav"),
+            alias: "- あ".to_string(),
+            offset: 100.0,
+            consonant: 150.0,
+            cutoff: -400.0,
+            preutterance: 80.0,
+            overlap: 30.0,
+        });
+        assert_eq!((entries[1].alias.as_str(), entries[1].offset, entries[1].cutoff, entries[1].overlap), ("_い", 50.5, 200.0, 0.0));
+    }
+
+    #[test]
+    fn test_read() {
+        let bank = temp("oto_bank");
+        let _ = std::fs::remove_dir_all(&bank);
+        std::fs::create_dir_all(bank.join("C4")).unwrap();
+        std::fs::write(bank.join("oto.ini"), "a.wav=a,0,50,0,20,5\n").unwrap();
+        // Shift-JIS "あ.wav=あ,10,50,0,20,5"
+        std::fs::write(bank.join("C4").join("oto.ini"), b"\x82\xa0.wav=\x82\xa0,10,50,0,20,5\n").unwrap();
+
+        let oto = Oto::read(&bank).unwrap();
+        assert_eq!(oto.get("あ").unwrap().file, PathBuf::from("C4/あ.wav"));
+        assert_eq!(oto.get("a").unwrap().offset, 0.0);
+        assert!(oto.get("い").is_none());
+        assert!(Oto::read(&bank.join("C4").join("missing")).is_err());
+    }
+}
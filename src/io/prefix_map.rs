@@ -0,0 +1,79 @@
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::io::text;
+use crate::util::misc::parse_note;
+
+/// A voicebank's `prefix.map`, which tells UTAU which prefix and suffix to add to a lyric on each note.
+/// Samples with a given prefix and suffix were recorded for the notes that map to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrefixMap {
+    /// MIDI note, prefix and suffix of every line
+    pub entries: Vec<(f64, String, String)>,
+}
+
+impl PrefixMap {
+    pub fn read(path: &Path) -> Result<PrefixMap> {
+        Ok(Self::parse(&text::decode(&std::fs::read(path)?)))
+    }
+
+    /// Parse `<note>\t<prefix>\t<suffix>` lines, skipping lines without a valid note
+    pub fn parse(text: &str) -> PrefixMap {
+        let entries = text.lines().filter_map(|line| {
+            let mut fields = line.split('\t');
+            let note = parse_note(fields.next()?)?;
+            Some((note, fields.next().unwrap_or("").to_string(), fields.next().unwrap_or("").to_string()))
+        }).collect();
+
+        PrefixMap { entries }
+    }
+
+    /// Prefix and suffix UTAU adds to lyrics on `note`
+    pub fn affixes(&self, note: f64) -> Option<(&str, &str)> {
+        self.entries.iter().find(|(n, _, _)| *n == note).map(|(_, p, s)| (p.as_str(), s.as_str()))
+    }
+
+    /// Middle of the range of notes whose prefix and suffix `alias` carries. Aliases matching several take the
+    /// longest, and the empty prefix and suffix say nothing about a sample's pitch.
+    pub fn base_note(&self, alias: &str) -> Option<f64> {
+        let (_, prefix, suffix) = self.entries.iter()
+            .filter(|(_, p, s)| !(p.is_empty() && s.is_empty()))
+            .filter(|(_, p, s)| alias.len() > p.len() + s.len() && alias.starts_with(p.as_str()) && alias.ends_with(s.as_str()))
+            .max_by_key(|(_, p, s)| p.len() + s.len())?;
+
+        let notes = self.entries.iter().filter(|(_, p, s)| p == prefix && s == suffix).map(|(n, _, _)| *n);
+        let (lo, hi) = notes.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), n| (lo.min(n), hi.max(n)));
+
+        Some((lo + hi) / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_note() {
+        let mut text = String::new();
+        for (octave, suffix) in [(3, "↓"), (4, ""), (5, "↑")] {
+            for note in ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"] {
+                text.push_str(&format!("{}{}\t\t{}\n", note, octave, suffix));
+            }
+        }
+        text.push_str("B7\t強\t\n");
+        let map = PrefixMap::parse(&text);
+
+        assert_eq!(map.entries.len(), 37);
+        assert_eq!(map.base_note("あ↑"), Some(77.5));
+        assert_eq!(map.base_note("_か↓"), Some(53.5));
+        assert_eq!(map.base_note("強い"), Some(107.0));
+        // The suffix alone isn't a sample, and plain aliases are left to analysis
+        assert_eq!(map.base_note("↑"), None);
+        assert_eq!(map.base_note("あ"), None);
+
+        assert_eq!(map.affixes(72.0), Some(("", "↑")));
+        assert_eq!(map.affixes(60.0), Some(("", "")));
+        assert_eq!(map.affixes(20.0), None);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,411 @@
+use std::fmt;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::features::Features;
+use crate::io::bin::encode;
+
+/// Reduced precision features can be stored at
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Precision {
+    /// Half-precision floats
+    F16,
+    /// 16-bit codes, scaled to the range of each dimension
+    Int16,
+    /// 8-bit codes, scaled to the range of each dimension
+    Int8,
+}
+
+impl Precision {
+    pub const ALL: [Precision; 3] = [Precision::F16, Precision::Int16, Precision::Int8];
+
+    /// Largest code of the scaled precisions
+    fn max_code(self) -> u32 {
+        match self {
+            Precision::Int8 => u8::MAX as u32,
+            _ => u16::MAX as u32,
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Precision::F16 => write!(f, "f16"),
+            Precision::Int16 => write!(f, "int16"),
+            Precision::Int8 => write!(f, "int8"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Codes {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+}
+
+impl Codes {
+    fn new(precision: Precision, codes: impl Iterator<Item = u32>) -> Codes {
+        match precision {
+            Precision::Int8 => Codes::U8(codes.map(|c| c as u8).collect()),
+            _ => Codes::U16(codes.map(|c| c as u16).collect()),
+        }
+    }
+
+    fn get(&self, i: usize) -> u32 {
+        match self {
+            Codes::U8(codes) => codes[i] as u32,
+            Codes::U16(codes) => codes[i] as u32,
+        }
+    }
+}
+
+/// A row-major matrix at reduced precision
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Matrix {
+    F16 { rows: u32, dims: u32, values: Vec<u16> },
+    /// `min + code * step` in each dimension. Rows with NaN or infinite values can't be scaled, and are kept
+    /// at half precision by row index instead.
+    Scaled { rows: u32, dims: u32, min: Vec<f64>, step: Vec<f64>, codes: Codes, f16_rows: Vec<(u32, Vec<u16>)> },
+}
+
+impl Matrix {
+    fn new(data: &[Vec<f64>], precision: Precision) -> Matrix {
+        let (rows, dims) = (data.len() as u32, data.first().map_or(0, |row| row.len()) as u32);
+        if precision == Precision::F16 {
+            return Matrix::F16 { rows, dims, values: data.iter().flatten().map(|v| to_f16(*v)).collect() };
+        }
+
+        let max_code = precision.max_code();
+        let (mut min, mut step) = (vec![], vec![]);
+        for d in 0..dims as usize {
+            let (lo, hi) = range(data.iter().map(|row| row[d]));
+            min.push(lo);
+            step.push((hi - lo) / max_code as f64);
+        }
+
+        let codes = data.iter().flat_map(|row| row.iter().enumerate()).map(|(d, v)| match step[d] > 0.0 && v.is_finite() {
+            true => ((v - min[d]) / step[d]).round().clamp(0.0, max_code as f64) as u32,
+            false => 0,
+        });
+        let f16_rows = data.iter().enumerate().filter(|(_, row)| row.iter().any(|v| !v.is_finite()))
+            .map(|(r, row)| (r as u32, row.iter().map(|v| to_f16(*v)).collect()))
+            .collect();
+        Matrix::Scaled { rows, dims, codes: Codes::new(precision, codes), min, step, f16_rows }
+    }
+
+    fn values(&self) -> Vec<Vec<f64>> {
+        match self {
+            Matrix::F16 { rows, dims, values } => (0..*rows as usize).map(|r| {
+                values[r * *dims as usize..(r + 1) * *dims as usize].iter().map(|v| from_f16(*v)).collect()
+            }).collect(),
+            Matrix::Scaled { rows, dims, min, step, codes, f16_rows } => {
+                let mut values = (0..*rows as usize).map(|r| {
+                    (0..*dims as usize).map(|d| min[d] + codes.get(r * *dims as usize + d) as f64 * step[d]).collect()
+                }).collect::<Vec<Vec<f64>>>();
+                for (r, row) in f16_rows {
+                    values[*r as usize] = row.iter().map(|v| from_f16(*v)).collect();
+                }
+                values
+            }
+        }
+    }
+}
+
+/// An F0 contour at reduced precision. Scaled contours are coded on a log scale, with code 0 for unvoiced
+/// frames, so every step is the same fraction of a semitone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Contour {
+    F16(Vec<u16>),
+    Scaled { min: f64, step: f64, codes: Codes },
+}
+
+impl Contour {
+    fn new(f0: &[f64], precision: Precision) -> Contour {
+        if precision == Precision::F16 {
+            return Contour::F16(f0.iter().map(|f| to_f16(*f)).collect());
+        }
+
+        let max_code = precision.max_code();
+        let (min, max) = range(f0.iter().filter(|f| **f > 0.0).map(|f| f.log2()));
+        let step = (max - min) / (max_code - 1) as f64;
+        let codes = f0.iter().map(|f| match (*f > 0.0, step > 0.0) {
+            (false, _) => 0,
+            (true, false) => 1,
+            (true, true) => 1 + ((f.log2() - min) / step).round().clamp(0.0, (max_code - 1) as f64) as u32,
+        });
+
+        Contour::Scaled { min, step, codes: Codes::new(precision, codes) }
+    }
+
+    fn values(&self, frames: usize) -> Vec<f64> {
+        match self {
+            Contour::F16(values) => values.iter().map(|v| from_f16(*v)).collect(),
+            Contour::Scaled { min, step, codes } => (0..frames).map(|i| match codes.get(i) {
+                0 => 0.0,
+                c => (min + (c - 1) as f64 * step).exp2(),
+            }).collect(),
+        }
+    }
+}
+
+/// Features with F0, mgc and bap at reduced precision
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuantizedFeatures {
+    base: f64,
+    frame_period: f64,
+    sample_rate: i32,
+    fft_size: i32,
+    frames: u32,
+    f0: Contour,
+    mgc: Matrix,
+    bap: Matrix,
+}
+
+impl QuantizedFeatures {
+    pub fn new(features: &Features, precision: Precision) -> QuantizedFeatures {
+        QuantizedFeatures {
+            base: features.base,
+            frame_period: features.frame_period,
+            sample_rate: features.sample_rate,
+            fft_size: features.fft_size,
+            frames: features.f0.len() as u32,
+            f0: Contour::new(&features.f0, precision),
+            mgc: Matrix::new(&features.mgc, precision),
+            bap: Matrix::new(&features.bap, precision),
+        }
+    }
+
+    pub fn features(&self) -> Features {
+        Features::new(
+            self.base,
+            self.frame_period,
+            self.sample_rate,
+            self.fft_size,
+            self.f0.values(self.frames as usize),
+            self.mgc.values(),
+            self.bap.values(),
+        )
+    }
+}
+
+/// Smallest and largest finite value, or zeros when there are none
+fn range(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    let (lo, hi) = values.filter(|v| v.is_finite()).fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    match lo <= hi {
+        true => (lo, hi),
+        false => (0.0, 0.0),
+    }
+}
+
+/// IEEE 754 half-precision bits of `value`, rounded to nearest even
+fn to_f16(value: f64) -> u16 {
+    let x = (value as f32).to_bits();
+    let sign = ((x >> 16) & 0x8000) as u16;
+    let exp = ((x >> 23) & 0xff) as i32;
+    let mant = x & 0x7f_ffff;
+
+    if exp == 0xff {
+        return sign | 0x7c00 | if mant != 0 { 0x200 } else { 0 };
+    }
+
+    let e = exp - 127 + 15;
+    if e >= 0x1f {
+        return sign | 0x7c00;
+    }
+    if e <= 0 {
+        // Subnormal, or too small even for that
+        if e < -10 {
+            return sign;
+        }
+        let m = mant | 0x80_0000;
+        let shift = (14 - e) as u32;
+        return sign | ((m + (1 << (shift - 1)) - 1 + ((m >> shift) & 1)) >> shift) as u16;
+    }
+
+    // A carry out of the mantissa correctly bumps the exponent, up to infinity
+    let rounded = mant + 0xfff + ((mant >> 13) & 1);
+    sign | (((e as u32) << 10) + (rounded >> 13)) as u16
+}
+
+fn from_f16(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((bits >> 10) & 0x1f) as i32;
+    let mant = (bits & 0x3ff) as f64;
+
+    sign * match exp {
+        0 => mant * 2f64.powi(-24),
+        0x1f if mant == 0.0 => f64::INFINITY,
+        0x1f => f64::NAN,
+        _ => (1.0 + mant / 1024.0) * 2f64.powi(exp - 15),
+    }
+}
+
+/// Mel-cepstral distortion in dB between two frames of mel-cepstral coefficients, leaving out the energy term
+pub fn mel_cepstral_distortion(a: &[f64], b: &[f64]) -> f64 {
+    let sum = a.iter().zip(b).skip(1).map(|(x, y)| (x - y).powi(2)).sum::<f64>();
+    10.0 / std::f64::consts::LN_10 * (2.0 * sum).sqrt()
+}
+
+/// How features fare at one precision, against the features as analyzed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    /// `None` for the features as analyzed
+    pub precision: Option<Precision>,
+    /// Total compressed size
+    pub bytes: usize,
+    /// Mean and largest mel-cepstral distortion of a frame with finite coefficients, in dB
+    pub mcd_mean: f64,
+    pub mcd_max: f64,
+    /// Largest F0 error of a voiced frame, in cents
+    pub f0_max_cents: f64,
+    /// RMS error of the coded aperiodicity
+    pub bap_rms: f64,
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.precision.map_or("none".to_string(), |p| p.to_string());
+        write!(
+            f, "{:<6} {:>9.2} MB  MCD mean {:.4} dB, max {:.4} dB  F0 max {:.3} cents  bap RMS {:.4}",
+            name, self.bytes as f64 / 1e6, self.mcd_mean, self.mcd_max, self.f0_max_cents, self.bap_rms,
+        )
+    }
+}
+
+/// Store `features` unquantized and at every precision, compressing at zstd `level`, and compare each with the
+/// features as analyzed
+pub fn measure(features: &[Features], level: i32) -> Result<Vec<Measurement>> {
+    let mut res = vec![];
+
+    for precision in [None].into_iter().chain(Precision::ALL.map(Some)) {
+        let mut m = Measurement { precision, bytes: 0, mcd_mean: 0.0, mcd_max: 0.0, f0_max_cents: 0.0, bap_rms: 0.0 };
+        let (mut frames, mut bap_frames, mut bap_sum) = (0, 0, 0.0);
+
+        for original in features {
+            let (serialized, stored) = match precision {
+                None => (bincode::serialize(original)?, original.clone()),
+                Some(p) => {
+                    let quantized = QuantizedFeatures::new(original, p);
+                    (bincode::serialize(&quantized)?, quantized.features())
+                }
+            };
+            let mut compressed = vec![];
+            encode(&serialized, &mut compressed, level)?;
+            m.bytes += compressed.len();
+
+            // Frames WORLD couldn't analyze, e.g. digital silence, have no meaningful distortion
+            for (a, b) in original.mgc.iter().zip(&stored.mgc).filter(|(a, _)| a.iter().all(|v| v.is_finite())) {
+                let mcd = mel_cepstral_distortion(a, b);
+                m.mcd_mean += mcd;
+                m.mcd_max = m.mcd_max.max(mcd);
+                frames += 1;
+            }
+            for (a, b) in original.f0.iter().zip(&stored.f0).filter(|(a, _)| **a > 0.0) {
+                m.f0_max_cents = m.f0_max_cents.max((1200.0 * (b / a).log2()).abs());
+            }
+            for (a, b) in original.bap.iter().flatten().zip(stored.bap.iter().flatten()) {
+                bap_sum += (a - b).powi(2);
+                bap_frames += 1;
+            }
+        }
+
+        m.mcd_mean /= frames.max(1) as f64;
+        m.bap_rms = (bap_sum / bap_frames.max(1) as f64).sqrt();
+        res.push(m);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features() -> Features {
+        let frames = 200;
+        Features::new(
+            220.0, 5.0, 44100, 2048,
+            (0..frames).map(|i| if i % 50 < 5 { 0.0 } else { 220.0 * (1.0 + 0.05 * (i as f64 * 0.1).sin()) }).collect(),
+            (0..frames).map(|i| (0..32).map(|k| -8.0 / (k + 1) as f64 + 0.3 * ((i * (k + 1)) as f64 * 0.01).cos()).collect()).collect(),
+            (0..frames).map(|i| vec![-20.0 + (i as f64 * 0.05).sin(), -0.5]).collect(),
+        )
+    }
+
+    #[test]
+    fn test_f16() {
+        for v in [0.0, 1.0, -2.5, 0.1, 220.0, 1760.0, 65504.0, 6.0e-8, -1.0e-5] {
+            let back = from_f16(to_f16(v));
+            assert!((back - v).abs() <= v.abs() / 1024.0 + 6.0e-8, "{} became {}", v, back);
+        }
+        assert_eq!(to_f16(1.0), 0x3c00);
+        assert_eq!(to_f16(-2.0), 0xc000);
+        assert_eq!(to_f16(1.0e6), 0x7c00);
+        // Halfway between 1 and the next half, ties go to even
+        assert_eq!(to_f16(1.0 + 1.0 / 2048.0), 0x3c00);
+        assert!(from_f16(to_f16(f64::NAN)).is_nan());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = features();
+
+        for precision in Precision::ALL {
+            let quantized = QuantizedFeatures::new(&original, precision);
+            let restored = bincode::deserialize::<QuantizedFeatures>(&bincode::serialize(&quantized).unwrap()).unwrap().features();
+
+            assert_eq!((restored.base, restored.frame_period, restored.sample_rate), (220.0, 5.0, 44100));
+            assert_eq!(restored.mgc.len(), original.mgc.len());
+            assert_eq!(restored.mgc[0].len(), 32);
+            // Voicing survives exactly, and pitch to within a few cents even at 8 bits
+            for (a, b) in original.f0.iter().zip(&restored.f0) {
+                assert_eq!(*a > 0.0, *b > 0.0);
+                if *a > 0.0 {
+                    assert!((1200.0 * (b / a).log2()).abs() < 1.0, "{:?}: {} became {}", precision, a, b);
+                }
+            }
+            // Constant dimensions come back exactly
+            assert!(restored.bap.iter().all(|row| row[1] == -0.5));
+        }
+    }
+
+    #[test]
+    fn test_non_finite_rows() {
+        // Frames WORLD couldn't analyze keep their NaN and infinite values rather than turning into the minimum
+        let mut original = features();
+        original.mgc[3] = vec![f64::NAN; 32];
+        original.mgc[7][5] = f64::NEG_INFINITY;
+
+        for precision in Precision::ALL {
+            let restored = QuantizedFeatures::new(&original, precision).features();
+            assert!(restored.mgc[3].iter().all(|v| v.is_nan()), "{:?}", precision);
+            assert_eq!(restored.mgc[7][5], f64::NEG_INFINITY);
+            assert!((restored.mgc[7][0] - original.mgc[7][0]).abs() < 1e-2);
+
+            // The other rows are scaled as if the non-finite values weren't there
+            let exact = QuantizedFeatures::new(&features(), precision).features();
+            assert_eq!(restored.mgc[8], exact.mgc[8]);
+        }
+    }
+
+    #[test]
+    fn test_measure() {
+        let measurements = measure(&[features()], 3).unwrap();
+        assert_eq!(measurements.len(), 4);
+
+        let [none, f16, int16, int8] = &measurements[..] else { unreachable!() };
+        assert_eq!((none.mcd_max, none.f0_max_cents, none.bap_rms), (0.0, 0.0, 0.0));
+        assert!(int16.mcd_max < f16.mcd_max.max(1e-3));
+        assert!(int8.mcd_mean > int16.mcd_mean && int8.mcd_mean < 0.1, "{}", int8);
+        assert!(int8.bytes < int16.bytes && int16.bytes < none.bytes);
+    }
+
+    #[test]
+    fn test_mel_cepstral_distortion() {
+        assert_eq!(mel_cepstral_distortion(&[1.0, 2.0], &[5.0, 2.0]), 0.0);
+        let mcd = mel_cepstral_distortion(&[0.0, 0.1, 0.0], &[0.0, 0.0, 0.1]);
+        assert!((mcd - 0.2 * 10.0 / std::f64::consts::LN_10).abs() < 1e-9);
+    }
+}
\ No newline at end of file
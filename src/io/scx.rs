@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Result};
+use memmap2::Mmap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::dsp::f0::PostProcess;
+use crate::io::bin::{deserialize, encode, DEFAULT_LEVEL};
+use crate::io::quant::Precision;
+
+/// First bytes of every versioned `.scx` file
+pub const MAGIC: [u8; 4] = *b"SCX\0";
+/// Current container version. Files without a header predate versioning and count as version 0.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// Magic, version, kind, compression and header length
+const PREAMBLE_LEN: usize = 12;
+
+#[derive(Debug, Copy, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// zstd level for feature files, from 1 (fastest) to 22 (smallest)
+    pub compression_level: i32,
+    /// Precision features are stored at, or full precision when unset
+    pub quantization: Option<Precision>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig { compression_level: DEFAULT_LEVEL, quantization: None }
+    }
+}
+
+impl CacheConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !(1..=22).contains(&self.compression_level) {
+            bail!("cache compression_level must be between 1 and 22, got {}", self.compression_level);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Features,
+    Database,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd,
+}
+
+/// Parameters the features were analyzed with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisParams {
+    pub frame_period: f64,
+    pub sample_rate: i32,
+    pub fft_size: i32,
+    pub mgc_dims: i32,
+    pub d4c_threshold: f64,
+    pub f0_floor: f64,
+    pub f0_ceil: f64,
+    pub channel: Option<u16>,
+    pub f0_source: F0Source,
+    pub f0_postprocess: PostProcess,
+    pub base_source: BaseSource,
+}
+
+/// Where the base frequency came from, with the MIDI note it was set to when it wasn't estimated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BaseSource {
+    /// From the analyzed F0
+    Estimated,
+    /// A folder named after a note, such as `C4/`
+    Folder(f64),
+    /// The voicebank's `prefix.map`
+    PrefixMap(f64),
+    /// The voicebank config
+    Override(f64),
+}
+
+impl BaseSource {
+    pub fn note(&self) -> Option<f64> {
+        match self {
+            BaseSource::Estimated => None,
+            BaseSource::Folder(note) | BaseSource::PrefixMap(note) | BaseSource::Override(note) => Some(*note),
+        }
+    }
+}
+
+/// Where the F0 contour came from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum F0Source {
+    Harvest,
+    /// An UTAU `.frq` map, identified by its SHA-256
+    Frq([u8; 32]),
+    /// DIO refined by StoneMask
+    Dio,
+}
+
+/// The audio file the features were analyzed from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    pub size: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    pub modified: u64,
+    pub sha256: [u8; 32],
+}
+
+impl SourceInfo {
+    pub fn of(file: &Path) -> Result<SourceInfo> {
+        let metadata = std::fs::metadata(file)?;
+        let modified = modified_nanos(&metadata)?;
+
+        Ok(SourceInfo { size: metadata.len(), modified, sha256: sha256(file)? })
+    }
+}
+
+pub fn sha256(file: &Path) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(file)?, &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+pub fn modified_nanos(metadata: &std::fs::Metadata) -> Result<u64> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u16,
+    pub kind: Kind,
+    pub compression: Compression,
+    /// Unset for databases, whose entries may have been analyzed at different rates
+    pub analysis: Option<AnalysisParams>,
+    pub source: Option<SourceInfo>,
+}
+
+impl Header {
+    pub fn new(kind: Kind, analysis: Option<AnalysisParams>, source: Option<SourceInfo>) -> Header {
+        Header { version: FORMAT_VERSION, kind, compression: Compression::Zstd, analysis, source }
+    }
+}
+
+/// Write `payload` (already serialized) behind `header`, compressed at zstd `level` if the header says so
+pub fn write(path: &Path, header: &Header, payload: &[u8], level: i32) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_preamble(&mut file, header)?;
+
+    match header.compression {
+        Compression::Zstd => encode(payload, &mut file, level)?,
+        Compression::None => file.write_all(payload)?,
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Read a `.scx` file into its header and payload as stored, to be passed to `bin::deserialize`.
+/// Files from before the container format have no header, and are returned whole as `None`.
+pub fn read(path: &Path) -> Result<(Option<Header>, Vec<u8>)> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+
+    match parse_preamble(&data, path)? {
+        Some((header, start)) => {
+            data.drain(..start);
+            Ok((Some(header), data))
+        }
+        None => Ok((None, data)),
+    }
+}
+
+/// Replace the header of the `.scx` at `path`, keeping `stored`, the payload `read` returned, as it is.
+/// The file is written next to `path` and moved into place, so readers never see a partial file.
+pub fn rewrite_header(path: &Path, header: &Header, stored: &[u8]) -> Result<()> {
+    let tmp = path.with_extension(format!("scx.{}.tmp", std::process::id()));
+    {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        write_preamble(&mut file, header)?;
+        file.write_all(stored)?;
+        file.flush()?;
+    }
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// Compress one entry for `write_archive`
+pub fn compress(payload: &[u8], compression: Compression, level: i32) -> Result<Vec<u8>> {
+    match compression {
+        Compression::Zstd => {
+            let mut res = vec![];
+            encode(payload, &mut res, level)?;
+            Ok(res)
+        }
+        Compression::None => Ok(payload.to_vec()),
+    }
+}
+
+/// Write an indexed archive of `(key, entry)` pairs, where each entry was compressed on its own by `compress`.
+/// The archive is written next to `path` and moved into place, so readers never see a partial file. Mappings
+/// of the file at `path` have to be released first, or moving it into place fails on Windows.
+pub fn write_archive<K: AsRef<str>, E: AsRef<[u8]>>(path: &Path, header: &Header, entries: &[(K, E)]) -> Result<()> {
+    let mut offset = 0u64;
+    let index = entries.iter().map(|(key, entry)| {
+        let len = entry.as_ref().len() as u64;
+        offset += len;
+        (key.as_ref(), offset - len, len)
+    }).collect::<Vec<(&str, u64, u64)>>();
+    let index = bincode::serialize(&index)?;
+
+    let tmp = path.with_extension(format!("scx.{}.tmp", std::process::id()));
+    {
+        let mut file = BufWriter::new(File::create(&tmp)?);
+        write_preamble(&mut file, header)?;
+        file.write_all(&(index.len() as u64).to_le_bytes())?;
+        file.write_all(&index)?;
+        for (_, entry) in entries {
+            file.write_all(entry.as_ref())?;
+        }
+        file.flush()?;
+    }
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+/// A memory-mapped archive written by `write_archive`. Only the index is read up front.
+pub struct Archive {
+    compression: Compression,
+    map: Mmap,
+    index: HashMap<String, Range<usize>>,
+}
+
+impl Archive {
+    /// Open an archive, or return `None` for unversioned files and the features of single samples
+    pub fn open(path: &Path) -> Result<Option<Archive>> {
+        let file = File::open(path)?;
+        // SAFETY: archives are never modified in place, only replaced by renaming a new file over them, which
+        // leaves the mapped file as it was on Unix and fails while it is mapped on Windows
+        let map = unsafe { Mmap::map(&file)? };
+
+        let Some((header, start)) = parse_preamble(&map, path)? else {
+            return Ok(None);
+        };
+        if header.kind != Kind::Database {
+            return Ok(None);
+        }
+
+        let Some(index_len) = map.get(start..start + 8) else {
+            bail!("{:?} is truncated", path);
+        };
+        let index_len = u64::from_le_bytes(index_len.try_into().unwrap()) as usize;
+        let Some(index) = map.get(start + 8..start + 8 + index_len) else {
+            bail!("{:?} is truncated", path);
+        };
+        let entries: Vec<(String, u64, u64)> = bincode::deserialize(index)?;
+
+        let data_start = start + 8 + index_len;
+        let mut index = HashMap::with_capacity(entries.len());
+        for (key, offset, len) in entries {
+            let range = data_start + offset as usize..data_start + (offset + len) as usize;
+            if range.end > map.len() {
+                bail!("{:?} is truncated", path);
+            }
+            index.insert(key, range);
+        }
+
+        Ok(Some(Archive { compression: header.compression, map, index }))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.index.keys()
+    }
+
+    /// An entry as stored, still compressed
+    pub fn raw(&self, key: &str) -> Option<&[u8]> {
+        self.index.get(key).map(|range| &self.map[range.clone()])
+    }
+
+    /// Decompress and deserialize a single entry
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.raw(key).map(|raw| deserialize(raw, self.compression == Compression::Zstd)).transpose()
+    }
+}
+
+fn write_preamble<W: Write>(w: &mut W, header: &Header) -> Result<()> {
+    let meta = bincode::serialize(&(&header.analysis, &header.source))?;
+
+    w.write_all(&MAGIC)?;
+    w.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    w.write_all(&[kind_to_byte(header.kind), compression_to_byte(header.compression)])?;
+    w.write_all(&(meta.len() as u32).to_le_bytes())?;
+    w.write_all(&meta)?;
+
+    Ok(())
+}
+
+/// Parse the header at the start of `data`, returning it with the offset of what follows.
+/// Data without the magic predates the container format and gives `None`.
+fn parse_preamble(data: &[u8], path: &Path) -> Result<Option<(Header, usize)>> {
+    if !data.starts_with(&MAGIC) {
+        return Ok(None);
+    }
+
+    if data.len() < PREAMBLE_LEN {
+        bail!("{:?} is truncated", path);
+    }
+
+    let version = u16::from_le_bytes([data[4], data[5]]);
+    if version > FORMAT_VERSION {
+        bail!("{:?} uses feature file format {}, but this straycrab reads up to {}; update straycrab or delete the file to regenerate it", path, version, FORMAT_VERSION);
+    }
+
+    let kind = match data[6] {
+        0 => Kind::Features,
+        1 => Kind::Database,
+        k => bail!("{:?} has unknown content kind {}", path, k),
+    };
+    let compression = match data[7] {
+        0 => Compression::None,
+        1 => Compression::Zstd,
+        c => bail!("{:?} has unknown compression {}", path, c),
+    };
+
+    let meta_len = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+    let Some(meta) = data.get(PREAMBLE_LEN..PREAMBLE_LEN + meta_len) else {
+        bail!("{:?} is truncated", path);
+    };
+    let (analysis, source) = bincode::deserialize(meta)?;
+
+    Ok(Some((Header { version, kind, compression, analysis, source }, PREAMBLE_LEN + meta_len)))
+}
+
+fn kind_to_byte(kind: Kind) -> u8 {
+    match kind {
+        Kind::Features => 0,
+        Kind::Database => 1,
+    }
+}
+
+fn compression_to_byte(compression: Compression) -> u8 {
+    match compression {
+        Compression::None => 0,
+        Compression::Zstd => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::bin::decode;
+    use crate::util::testing::temp;
+
+    fn header(compression: Compression) -> Header {
+        let analysis = AnalysisParams {
+            frame_period: 5.0,
+            sample_rate: 44100,
+            fft_size: 2048,
+            mgc_dims: 64,
+            d4c_threshold: 0.25,
+            f0_floor: 71.0,
+            f0_ceil: 1760.0,
+            channel: None,
+            f0_source: F0Source::Frq([3; 32]),
+            f0_postprocess: PostProcess { median_window: 15.0, ..PostProcess::default() },
+            base_source: BaseSource::Folder(60.0),
+        };
+        let source = SourceInfo { size: 1234, modified: 5678, sha256: [7; 32] };
+
+        Header { compression, ..Header::new(Kind::Features, Some(analysis), Some(source)) }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let payload = (0..1000u32).flat_map(|i| (i % 7).to_le_bytes()).collect::<Vec<u8>>();
+
+        for compression in [Compression::Zstd, Compression::None] {
+            let path = temp(&format!("round_trip_{:?}.scx", compression));
+            write(&path, &header(compression), &payload, DEFAULT_LEVEL).unwrap();
+
+            let raw = std::fs::read(&path).unwrap();
+            assert!(raw.starts_with(&MAGIC));
+            assert_eq!(raw.len() < payload.len(), compression == Compression::Zstd);
+
+            let (read_header, stored) = read(&path).unwrap();
+            assert_eq!(read_header, Some(header(compression)));
+            match compression {
+                Compression::Zstd => assert_eq!(decode(stored.as_slice()).unwrap(), payload),
+                Compression::None => assert_eq!(stored, payload),
+            }
+        }
+    }
+
+    #[test]
+    fn test_legacy_detection() {
+        let payload = b"legacy bincode".to_vec();
+
+        let path = temp("legacy_raw.scx");
+        std::fs::write(&path, &payload).unwrap();
+        assert_eq!(read(&path).unwrap(), (None, payload.clone()));
+
+        let path = temp("legacy_zstd.scx");
+        encode(&payload, File::create(&path).unwrap(), DEFAULT_LEVEL).unwrap();
+        let (header, stored) = read(&path).unwrap();
+        assert_eq!(header, None);
+        assert_eq!(decode(stored.as_slice()).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_rejects_newer_and_truncated_files() {
+        let path = temp("newer.scx");
+        write(&path, &header(Compression::None), b"data", DEFAULT_LEVEL).unwrap();
+
+        let mut raw = std::fs::read(&path).unwrap();
+        raw[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        std::fs::write(&path, &raw).unwrap();
+        assert!(read(&path).unwrap_err().to_string().contains("update straycrab"));
+
+        std::fs::write(&path, &raw[..14]).unwrap();
+        assert!(read(&path).is_err());
+    }
+
+    #[test]
+    fn test_archive() {
+        let path = temp("archive.scx");
+        let header = Header::new(Kind::Database, None, None);
+        let entries = [("a.wav", b"first".to_vec()), ("C4/a.wav", vec![3; 5000]), ("empty.wav", vec![])];
+        let stored = entries.iter()
+            .map(|(k, v)| (*k, compress(&bincode::serialize(v).unwrap(), Compression::Zstd, DEFAULT_LEVEL).unwrap()))
+            .collect::<Vec<_>>();
+
+        write_archive(&path, &header, &stored).unwrap();
+        let archive = Archive::open(&path).unwrap().unwrap();
+
+        assert_eq!(read(&path).unwrap().0, Some(header));
+        assert_eq!(archive.keys().count(), 3);
+        for (key, value) in &entries {
+            assert_eq!(archive.get::<Vec<u8>>(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(archive.raw("C4/a.wav"), Some(stored[1].1.as_slice()));
+        assert_eq!(archive.get::<Vec<u8>>("missing.wav").unwrap(), None);
+
+        // Single-sample files are not archives. The mapping is released first, as the file is written in place.
+        drop(archive);
+        write(&path, &Header::new(Kind::Features, None, None), b"features", DEFAULT_LEVEL).unwrap();
+        assert!(Archive::open(&path).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_source_info() {
+        let path = temp("source.bin");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let info = SourceInfo::of(&path).unwrap();
+        assert_eq!(info.size, 3);
+        // SHA-256 of "abc"
+        assert_eq!(info.sha256[..4], [0xba, 0x78, 0x16, 0xbf]);
+    }
+}
@@ -0,0 +1,23 @@
+/// Decode a text file UTAU may have written: UTF-8 with or without a byte order mark, or otherwise Shift-JIS,
+/// which older UTAU versions and most voicebanks use
+pub fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => encoding_rs::SHIFT_JIS.decode(bytes).0.into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode("C4\t\t↑".as_bytes()), "C4\t\t↑");
+        assert_eq!(decode(b"\xef\xbb\xbfC4"), "C4");
+        // "あ↑" in Shift-JIS
+        assert_eq!(decode(b"\x82\xa0\x81\xaa"), "あ↑");
+    }
+}
\ No newline at end of file
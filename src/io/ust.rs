@@ -1 +1,741 @@
-// Future: Add support for rendering from UST project files
\ No newline at end of file
+use std::fmt::{self, Display};
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use encoding_rs::Encoding;
+
+use crate::io::text;
+
+/// Ticks per quarter note
+pub const RESOLUTION: u32 = 480;
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// An UTAU project (`.ust`), version 1.2 or 2.0, in Shift-JIS or UTF-8
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ust {
+    /// `1.2` or `2.0`; `None` for files without a `[#VERSION]` section
+    pub version: Option<String>,
+    /// Encoding declared in `[#VERSION]`, such as `UTF-8`. Files without one are Shift-JIS.
+    pub charset: Option<String>,
+    pub settings: Settings,
+    pub notes: Vec<Note>,
+}
+
+/// The `[#SETTING]` section
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// Beats per minute at the start of the project
+    pub tempo: f64,
+    pub project_name: Option<String>,
+    /// Voicebank folder, where `%VOICE%` stands for UTAU's `voice` folder
+    pub voice_dir: Option<String>,
+    pub out_file: Option<String>,
+    pub cache_dir: Option<String>,
+    /// Wavtool
+    pub tool1: Option<String>,
+    /// Resampler
+    pub tool2: Option<String>,
+    /// Flags passed with every note, before its own
+    pub flags: Option<String>,
+    /// Whether pitch is drawn as Mode2 curves (`PBS`, `PBW`, ...) instead of Mode1 `PitchBend` values
+    pub mode2: bool,
+    /// Keys this model doesn't cover, in file order
+    pub other: Vec<(String, String)>,
+}
+
+/// A `[#0000]` section. Rests have the lyric `R`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    /// Ticks, `RESOLUTION` per quarter note
+    pub length: u32,
+    pub lyric: String,
+    /// MIDI note number
+    pub note_num: i32,
+    /// Consonant velocity in percent
+    pub velocity: Option<f64>,
+    /// Volume in percent
+    pub intensity: Option<f64>,
+    /// How much of the sample's own pitch movement is kept, in percent
+    pub modulation: Option<f64>,
+    pub flags: Option<String>,
+    /// Preutterance in ms, replacing the one from the oto
+    pub pre_utterance: Option<f64>,
+    /// Overlap in ms, replacing the one from the oto
+    pub voice_overlap: Option<f64>,
+    /// ms added to the oto's offset
+    pub start_point: Option<f64>,
+    /// Beats per minute from this note on
+    pub tempo: Option<f64>,
+    pub envelope: Option<Envelope>,
+    /// Mode1 pitch
+    pub pitch_bend: Option<PitchBend>,
+    /// Mode2 pitch curve
+    pub portamento: Option<Portamento>,
+    pub vibrato: Option<Vibrato>,
+    /// Keys this model doesn't cover, in file order
+    pub other: Vec<(String, String)>,
+}
+
+/// Volume envelope, written `p1,p2,p3,v1,v2,v3,v4,%,p4,p5,v5`. Times are in ms and volumes in percent; the
+/// envelope passes through `v1` at `p1`, `v2` at `p1 + p2`, `v5` at `p1 + p2 + p5`, `v3` at `p4 + p3`
+/// before the end and `v4` at `p4` before the end. The `%` stands for the overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub p1: f64,
+    pub p2: f64,
+    pub p3: f64,
+    pub v1: f64,
+    pub v2: f64,
+    pub v3: f64,
+    pub v4: f64,
+    pub p4: Option<f64>,
+    pub p5: Option<f64>,
+    pub v5: Option<f64>,
+}
+
+/// Mode1 pitch: offsets from the note's pitch at fixed intervals
+#[derive(Debug, Clone, PartialEq)]
+pub struct PitchBend {
+    /// `PBType`, the ticks between values; UTAU writes 5
+    pub interval: Option<String>,
+    /// `PBStart`, ms from the note start where the values begin
+    pub start: Option<f64>,
+    /// `PitchBend`, in cents
+    pub cents: Vec<f64>,
+}
+
+/// Mode2 pitch curve through control points, each relative to the note's start and pitch
+#[derive(Debug, Clone, PartialEq)]
+pub struct Portamento {
+    /// `PBS`, ms from the note start of the first point
+    pub start: f64,
+    /// `PBS`, pitch of the first point in tenths of a semitone
+    pub start_pitch: f64,
+    /// `PBW`, ms from each point to the next
+    pub widths: Vec<f64>,
+    /// `PBY`, pitch of the points after the first in tenths of a semitone. The last point is at the note's
+    /// pitch when not given.
+    pub heights: Vec<f64>,
+    /// `PBM`, shape of each segment
+    pub shapes: Vec<CurveShape>,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum CurveShape {
+    /// Sine ease in and out, written as an empty entry
+    #[default]
+    S,
+    /// Straight line, `s`
+    Linear,
+    /// Ease out, `r`
+    R,
+    /// Ease in, `j`
+    J,
+}
+
+/// `VBR`, vibrato over the end of the note
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vibrato {
+    /// Share of the note covered, in percent
+    pub length: f64,
+    /// ms per cycle
+    pub cycle: f64,
+    /// Cents
+    pub depth: f64,
+    /// Share of the vibrato fading in and out, in percent
+    pub fade_in: f64,
+    pub fade_out: f64,
+    /// Starting phase in percent of a cycle
+    pub phase: f64,
+    /// Offset of the center in percent of the depth
+    pub offset: f64,
+}
+
+impl Ust {
+    pub fn read(path: &Path) -> Result<Ust> {
+        Self::parse(&Self::decode(&std::fs::read(path)?)).map_err(|e| anyhow!("Failed to read {:?}: {}", path, e))
+    }
+
+    /// Decode a project in the charset its `[#VERSION]` section declares, or as `text::decode` guesses when it
+    /// declares none or one that isn't known. The declaration itself is ASCII, so it can be found first.
+    fn decode(bytes: &[u8]) -> String {
+        let ascii = String::from_utf8_lossy(bytes);
+        let charset = sections(&ascii).into_iter().filter(|(name, _)| *name == "#VERSION")
+            .flat_map(|(_, lines)| pairs(lines))
+            .find_map(|(key, value)| (key == "Charset").then(|| Encoding::for_label(value.trim().as_bytes())).flatten());
+
+        match charset {
+            Some(encoding) => encoding.decode(bytes).0.into_owned(),
+            None => text::decode(bytes),
+        }
+    }
+
+    /// Parse a project. Sections other than the version, settings and numbered notes, such as the
+    /// `[#PREV]` and `[#NEXT]` of plugin files, are skipped.
+    pub fn parse(text: &str) -> Result<Ust> {
+        let mut ust = Ust { version: None, charset: None, settings: Settings::default(), notes: vec![] };
+
+        for (name, lines) in sections(text) {
+            match name {
+                "#VERSION" => for line in lines {
+                    if let Some(version) = line.strip_prefix("UST Version") {
+                        ust.version = Some(version.trim().to_string());
+                    } else if let Some(("Charset", charset)) = line.split_once('=') {
+                        ust.charset = Some(charset.to_string());
+                    }
+                },
+                "#SETTING" => ust.settings = Settings::parse(&pairs(lines))?,
+                _ if name.len() > 1 && name[1..].bytes().all(|b| b.is_ascii_digit()) => {
+                    ust.notes.push(Note::parse(&pairs(lines)).map_err(|e| anyhow!("[{}] {}", name, e))?);
+                }
+                _ => log::debug!("Skipping UST section [{}]", name),
+            }
+        }
+
+        Ok(ust)
+    }
+
+    /// Only tests write projects so far, to check that they read back unchanged
+    #[cfg(test)]
+    pub fn write(&self, path: &Path) -> Result<()> {
+        Ok(std::fs::write(path, self.encode())?)
+    }
+
+    /// The project as UTAU reads it: in the declared charset, or Shift-JIS when none is declared. Projects
+    /// with text the charset can't hold are written as UTF-8 and declared so.
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let encoding = self.charset.as_ref().and_then(|c| Encoding::for_label(c.as_bytes())).unwrap_or(encoding_rs::SHIFT_JIS);
+        if encoding != encoding_rs::UTF_8 {
+            let text = self.to_string();
+            let (bytes, _, unmappable) = encoding.encode(&text);
+            if !unmappable {
+                return bytes.into_owned();
+            }
+        }
+
+        let declared = Ust { charset: Some("UTF-8".to_string()), ..self.clone() };
+        declared.to_string().into_bytes()
+    }
+
+    /// Ticks at which each note starts
+    #[cfg(test)]
+    pub fn positions(&self) -> Vec<u32> {
+        self.notes.iter().scan(0, |tick, note| {
+            let start = *tick;
+            *tick += note.length;
+            Some(start)
+        }).collect()
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            tempo: DEFAULT_TEMPO,
+            project_name: None,
+            voice_dir: None,
+            out_file: None,
+            cache_dir: None,
+            tool1: None,
+            tool2: None,
+            flags: None,
+            mode2: false,
+            other: vec![],
+        }
+    }
+}
+
+impl Settings {
+    fn parse(pairs: &[(&str, &str)]) -> Result<Settings> {
+        let mut settings = Settings::default();
+
+        for &(key, value) in pairs {
+            match key {
+                "Tempo" => settings.tempo = parse_tempo(value)?,
+                "ProjectName" => settings.project_name = Some(value.to_string()),
+                "VoiceDir" => settings.voice_dir = Some(value.to_string()),
+                "OutFile" => settings.out_file = Some(value.to_string()),
+                "CacheDir" => settings.cache_dir = Some(value.to_string()),
+                "Tool1" => settings.tool1 = Some(value.to_string()),
+                "Tool2" => settings.tool2 = Some(value.to_string()),
+                "Flags" => settings.flags = Some(value.to_string()),
+                "Mode2" => settings.mode2 = value.eq_ignore_ascii_case("true"),
+                _ => settings.other.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+impl Note {
+    fn parse(pairs: &[(&str, &str)]) -> Result<Note> {
+        let mut note = Note {
+            length: 0,
+            lyric: String::new(),
+            note_num: 0,
+            velocity: None,
+            intensity: None,
+            modulation: None,
+            flags: None,
+            pre_utterance: None,
+            voice_overlap: None,
+            start_point: None,
+            tempo: None,
+            envelope: None,
+            pitch_bend: None,
+            portamento: None,
+            vibrato: None,
+            other: vec![],
+        };
+        let (mut length, mut note_num) = (None, None);
+        let (mut pbs, mut pbw, mut pby, mut pbm) = (None, None, None, None);
+
+        for &(key, value) in pairs {
+            let pitch_bend = || PitchBend { interval: None, start: None, cents: vec![] };
+            match key {
+                "Length" => length = Some(number(key, value)?),
+                "Lyric" => note.lyric = value.to_string(),
+                "NoteNum" => note_num = Some(number(key, value)?),
+                "Velocity" => note.velocity = optional(key, value)?,
+                "Intensity" => note.intensity = optional(key, value)?,
+                "Modulation" | "Moduration" => note.modulation = optional(key, value)?,
+                "Flags" => note.flags = Some(value.to_string()),
+                "PreUtterance" => note.pre_utterance = optional(key, value)?,
+                "VoiceOverlap" => note.voice_overlap = optional(key, value)?,
+                "StartPoint" => note.start_point = optional(key, value)?,
+                "Tempo" => note.tempo = Some(parse_tempo(value)?),
+                "Envelope" => note.envelope = Some(Envelope::parse(value)?),
+                "PBType" => note.pitch_bend.get_or_insert_with(pitch_bend).interval = Some(value.to_string()),
+                "PBStart" => note.pitch_bend.get_or_insert_with(pitch_bend).start = optional(key, value)?,
+                "PitchBend" | "Piches" => note.pitch_bend.get_or_insert_with(pitch_bend).cents = list(key, value)?,
+                "PBS" => pbs = Some(value),
+                "PBW" => pbw = Some(list(key, value)?),
+                "PBY" => pby = Some(list(key, value)?),
+                "PBM" => pbm = Some(value.split(',').map(CurveShape::parse).collect::<Vec<_>>()),
+                "VBR" => note.vibrato = Some(Vibrato::parse(value)?),
+                _ => note.other.push((key.to_string(), value.to_string())),
+            }
+        }
+
+        note.length = length.ok_or_else(|| anyhow!("Length is missing"))?;
+        note.note_num = note_num.ok_or_else(|| anyhow!("NoteNum is missing"))?;
+        if let Some(pbs) = pbs {
+            let (start, start_pitch) = pbs.split_once(';').unwrap_or((pbs, ""));
+            note.portamento = Some(Portamento {
+                start: number("PBS", start)?,
+                start_pitch: optional("PBS", start_pitch)?.unwrap_or(0.0),
+                widths: pbw.unwrap_or_default(),
+                heights: pby.unwrap_or_default(),
+                shapes: pbm.unwrap_or_default(),
+            });
+        }
+
+        Ok(note)
+    }
+
+    pub fn is_rest(&self) -> bool {
+        self.lyric == "R" || self.lyric == "r"
+    }
+}
+
+impl Envelope {
+    fn parse(value: &str) -> Result<Envelope> {
+        let fields = value.split(',').map(str::trim).collect::<Vec<&str>>();
+        if fields.len() < 7 {
+            bail!("Envelope needs at least 7 values, got {:?}", value);
+        }
+        let at = |i: usize| -> Result<Option<f64>> {
+            fields.get(i).map_or(Ok(None), |v| optional("Envelope", v))
+        };
+        let required = |i: usize| -> Result<f64> { Ok(at(i)?.unwrap_or(0.0)) };
+
+        Ok(Envelope {
+            p1: required(0)?,
+            p2: required(1)?,
+            p3: required(2)?,
+            v1: required(3)?,
+            v2: required(4)?,
+            v3: required(5)?,
+            v4: required(6)?,
+            // Field 7 is the overlap placeholder
+            p4: at(8)?,
+            p5: at(9)?,
+            v5: at(10)?,
+        })
+    }
+}
+
+impl Envelope {
+    /// Points in time order as (ms, percent) for a note rendered `duration` ms long. `v5` is 100 unless given.
+    pub fn points(&self, duration: f64) -> Vec<(f64, f64)> {
+        let p4 = self.p4.unwrap_or(0.0);
+        let mut points = vec![(self.p1, self.v1), (self.p1 + self.p2, self.v2)];
+        if let Some(p5) = self.p5 {
+            points.push((self.p1 + self.p2 + p5, self.v5.unwrap_or(100.0)));
+        }
+        points.extend([(duration - p4 - self.p3, self.v3), (duration - p4, self.v4)]);
+
+        // Points overlapping on short notes keep their order
+        for i in 1..points.len() {
+            points[i].0 = points[i].0.max(points[i - 1].0);
+        }
+
+        points
+    }
+}
+
+impl Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{},{},{},{}", self.p1, self.p2, self.p3, self.v1, self.v2, self.v3, self.v4)?;
+        let tail = [self.p4, self.p5, self.v5];
+        let given = tail.iter().rposition(Option::is_some).map_or(0, |i| i + 1);
+        if given > 0 {
+            write!(f, ",%")?;
+        }
+        for value in &tail[..given] {
+            write!(f, ",{}", value.map_or(String::new(), |v| v.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CurveShape {
+    fn parse(value: &str) -> CurveShape {
+        match value.trim() {
+            "s" => CurveShape::Linear,
+            "r" => CurveShape::R,
+            "j" => CurveShape::J,
+            _ => CurveShape::S,
+        }
+    }
+}
+
+impl Display for CurveShape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CurveShape::S => "",
+            CurveShape::Linear => "s",
+            CurveShape::R => "r",
+            CurveShape::J => "j",
+        })
+    }
+}
+
+impl Vibrato {
+    fn parse(value: &str) -> Result<Vibrato> {
+        let fields = list("VBR", value)?;
+        if fields.len() < 7 {
+            bail!("VBR needs at least 7 values, got {:?}", value);
+        }
+
+        Ok(Vibrato {
+            length: fields[0],
+            cycle: fields[1],
+            depth: fields[2],
+            fade_in: fields[3],
+            fade_out: fields[4],
+            phase: fields[5],
+            offset: fields[6],
+        })
+    }
+}
+
+impl Display for Vibrato {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The eighth value is unused, and UTAU writes 0
+        write!(f, "{},{},{},{},{},{},{},0", self.length, self.cycle, self.depth, self.fade_in, self.fade_out, self.phase, self.offset)
+    }
+}
+
+impl Display for Ust {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.version.is_some() || self.charset.is_some() {
+            write!(f, "[#VERSION]\r\n")?;
+            if let Some(version) = &self.version {
+                write!(f, "UST Version{}\r\n", version)?;
+            }
+            if let Some(charset) = &self.charset {
+                write!(f, "Charset={}\r\n", charset)?;
+            }
+        }
+
+        let s = &self.settings;
+        write!(f, "[#SETTING]\r\nTempo={}\r\n", tempo(s.tempo))?;
+        let optional = [
+            ("ProjectName", &s.project_name), ("VoiceDir", &s.voice_dir), ("OutFile", &s.out_file),
+            ("CacheDir", &s.cache_dir), ("Tool1", &s.tool1), ("Tool2", &s.tool2), ("Flags", &s.flags),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                write!(f, "{}={}\r\n", key, value)?;
+            }
+        }
+        if s.mode2 {
+            write!(f, "Mode2=True\r\n")?;
+        }
+        for (key, value) in &s.other {
+            write!(f, "{}={}\r\n", key, value)?;
+        }
+
+        for (i, note) in self.notes.iter().enumerate() {
+            write!(f, "[#{:04}]\r\n{}", i, note)?;
+        }
+        write!(f, "[#TRACKEND]\r\n")
+    }
+}
+
+impl Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Length={}\r\nLyric={}\r\nNoteNum={}\r\n", self.length, self.lyric, self.note_num)?;
+
+        let numbers = [
+            ("PreUtterance", self.pre_utterance), ("VoiceOverlap", self.voice_overlap), ("Velocity", self.velocity),
+            ("Intensity", self.intensity), ("Modulation", self.modulation), ("StartPoint", self.start_point),
+        ];
+        for (key, value) in numbers {
+            if let Some(value) = value {
+                write!(f, "{}={}\r\n", key, value)?;
+            }
+        }
+        if let Some(tempo) = self.tempo {
+            write!(f, "Tempo={}\r\n", self::tempo(tempo))?;
+        }
+        if let Some(flags) = &self.flags {
+            write!(f, "Flags={}\r\n", flags)?;
+        }
+
+        if let Some(pitch) = &self.pitch_bend {
+            if let Some(interval) = &pitch.interval {
+                write!(f, "PBType={}\r\n", interval)?;
+            }
+            if let Some(start) = pitch.start {
+                write!(f, "PBStart={}\r\n", start)?;
+            }
+            write!(f, "PitchBend={}\r\n", join(&pitch.cents))?;
+        }
+        if let Some(p) = &self.portamento {
+            write!(f, "PBS={};{}\r\nPBW={}\r\n", p.start, p.start_pitch, join(&p.widths))?;
+            if !p.heights.is_empty() {
+                write!(f, "PBY={}\r\n", join(&p.heights))?;
+            }
+            if !p.shapes.is_empty() {
+                write!(f, "PBM={}\r\n", join(&p.shapes))?;
+            }
+        }
+        if let Some(vibrato) = &self.vibrato {
+            write!(f, "VBR={}\r\n", vibrato)?;
+        }
+        if let Some(envelope) = &self.envelope {
+            write!(f, "Envelope={}\r\n", envelope)?;
+        }
+
+        for (key, value) in &self.other {
+            write!(f, "{}={}\r\n", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Sections by name without brackets, with their non-empty lines
+fn sections(text: &str) -> Vec<(&str, Vec<&str>)> {
+    let mut sections: Vec<(&str, Vec<&str>)> = vec![];
+
+    for line in text.lines().map(str::trim_end).filter(|l| !l.is_empty()) {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name, vec![]));
+        } else if let Some((_, lines)) = sections.last_mut() {
+            lines.push(line);
+        }
+    }
+
+    sections
+}
+
+/// `key=value` lines, skipping any without a `=`
+fn pairs(lines: Vec<&str>) -> Vec<(&str, &str)> {
+    lines.into_iter().filter_map(|line| line.split_once('=')).collect()
+}
+
+fn number<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.trim().parse().map_err(|_| anyhow!("{} is not a number: {:?}", key, value))
+}
+
+/// A number that may be left empty
+fn optional(key: &str, value: &str) -> Result<Option<f64>> {
+    match value.trim() {
+        "" => Ok(None),
+        value => number(key, value).map(Some),
+    }
+}
+
+/// Comma-separated numbers, where empty entries are 0
+fn list(key: &str, value: &str) -> Result<Vec<f64>> {
+    if value.trim().is_empty() {
+        return Ok(vec![]);
+    }
+
+    value.split(',').map(|v| optional(key, v).map(Option::unwrap_or_default)).collect()
+}
+
+/// Tempos are written with a decimal comma in some locales
+fn parse_tempo(value: &str) -> Result<f64> {
+    let tempo: f64 = number("Tempo", &value.replace(',', "."))?;
+    if tempo.is_nan() || tempo <= 0.0 {
+        bail!("Tempo must be positive, got {}", value);
+    }
+
+    Ok(tempo)
+}
+
+/// A tempo with two decimals like UTAU writes it, or more when it has them
+fn tempo(value: f64) -> String {
+    let fixed = format!("{:.2}", value);
+    match fixed.parse::<f64>() == Ok(value) {
+        true => fixed,
+        false => value.to_string(),
+    }
+}
+
+fn join<T: Display>(values: &[T]) -> String {
+    values.iter().map(T::to_string).collect::<Vec<String>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::testing::temp;
+
+    const MODE1: &str = "[#VERSION]\r\nUST Version1.2\r\n[#SETTING]\r\nTempo=120.00\r\nTracks=1\r\nProjectName=test\r\n\
+        VoiceDir=%VOICE%uta\r\nTool1=wavtool.exe\r\nTool2=straycrab.exe\r\nFlags=g-5\r\n\
+        [#0000]\r\nLength=480\r\nLyric=R\r\nNoteNum=60\r\n\
+        [#0001]\r\nLength=960\r\nLyric=あ\r\nNoteNum=62\r\nPreUtterance=\r\nVelocity=120\r\nIntensity=80\r\nModulation=0\r\n\
+        Flags=B20\r\nPBType=5\r\nPBStart=-25.5\r\nPitchBend=-100,-50,,0\r\nEnvelope=5,35,0,100,100,100,100,%,0\r\n\
+        [#0002]\r\nLength=240\r\nLyric=い\r\nNoteNum=64\r\nPreUtterance=40\r\nVoiceOverlap=10\r\nTempo=140,00\r\n\
+        Envelope=0,5,35,0,100,100,0\r\n@alias=い\r\n[#TRACKEND]\r\n";
+
+    const MODE2: &str = "[#VERSION]\nUST Version2.0\nCharset=UTF-8\n[#SETTING]\nTempo=150\nVoiceDir=%VOICE%uta\nMode2=True\n\
+        [#0000]\nLength=480\nLyric=ら\nNoteNum=67\nPBS=-40;-20\nPBW=80,40\nPBY=5\nPBM=,r\nVBR=65,180,35,20,20,0,0,0\n\
+        Envelope=5,35,0,100,100,100,100,%,0,10,90\n[#0001]\nLength=480\nLyric=ら\nNoteNum=67\nPBS=-30\nPBW=60\n[#TRACKEND]\n";
+
+    #[test]
+    fn test_parse_mode1() {
+        let ust = Ust::parse(MODE1).unwrap();
+        assert_eq!((ust.version.as_deref(), ust.charset.as_deref()), (Some("1.2"), None));
+
+        let s = &ust.settings;
+        assert_eq!((s.tempo, s.voice_dir.as_deref(), s.flags.as_deref(), s.mode2), (120.0, Some("%VOICE%uta"), Some("g-5"), false));
+        assert_eq!((s.tool2.as_deref(), &s.other[..]), (Some("straycrab.exe"), &[("Tracks".to_string(), "1".to_string())][..]));
+
+        assert_eq!(ust.notes.len(), 3);
+        assert!(ust.notes[0].is_rest());
+        assert_eq!(ust.positions(), [0, 480, 1440]);
+
+        let a = &ust.notes[1];
+        assert_eq!((a.length, a.lyric.as_str(), a.note_num), (960, "あ", 62));
+        assert_eq!((a.velocity, a.intensity, a.modulation, a.pre_utterance), (Some(120.0), Some(80.0), Some(0.0), None));
+        assert_eq!(a.pitch_bend, Some(PitchBend { interval: Some("5".to_string()), start: Some(-25.5), cents: vec![-100.0, -50.0, 0.0, 0.0] }));
+        let envelope = a.envelope.as_ref().unwrap();
+        assert_eq!((envelope.p2, envelope.v4, envelope.p4, envelope.p5), (35.0, 100.0, Some(0.0), None));
+
+        let i = &ust.notes[2];
+        assert_eq!((i.pre_utterance, i.voice_overlap, i.tempo), (Some(40.0), Some(10.0), Some(140.0)));
+        assert_eq!((i.envelope.as_ref().unwrap().p4, &i.other[..]), (None, &[("@alias".to_string(), "い".to_string())][..]));
+    }
+
+    #[test]
+    fn test_parse_mode2() {
+        let ust = Ust::parse(MODE2).unwrap();
+        assert_eq!((ust.version.as_deref(), ust.charset.as_deref(), ust.settings.mode2), (Some("2.0"), Some("UTF-8"), true));
+
+        let note = &ust.notes[0];
+        assert_eq!(note.portamento, Some(Portamento {
+            start: -40.0,
+            start_pitch: -20.0,
+            widths: vec![80.0, 40.0],
+            heights: vec![5.0],
+            shapes: vec![CurveShape::S, CurveShape::R],
+        }));
+        let vibrato = note.vibrato.as_ref().unwrap();
+        assert_eq!((vibrato.length, vibrato.cycle, vibrato.depth, vibrato.offset), (65.0, 180.0, 35.0, 0.0));
+        assert_eq!(note.envelope.as_ref().unwrap().v5, Some(90.0));
+
+        let portamento = ust.notes[1].portamento.as_ref().unwrap();
+        assert_eq!((portamento.start, portamento.start_pitch, portamento.heights.len()), (-30.0, 0.0, 0));
+    }
+
+    #[test]
+    fn test_envelope_points() {
+        let envelope = Ust::parse(MODE2).unwrap().notes[0].envelope.clone().unwrap();
+        assert_eq!(envelope.points(200.0), [(5.0, 100.0), (40.0, 100.0), (50.0, 90.0), (200.0, 100.0), (200.0, 100.0)]);
+
+        let envelope = Envelope::parse("0,5,35,0,100,100,0,%,10").unwrap();
+        assert_eq!(envelope.points(200.0), [(0.0, 0.0), (5.0, 100.0), (155.0, 100.0), (190.0, 0.0)]);
+        // Too short for the fades, which then meet
+        assert_eq!(envelope.points(30.0), [(0.0, 0.0), (5.0, 100.0), (5.0, 100.0), (20.0, 0.0)]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for text in [MODE1, MODE2] {
+            let ust = Ust::parse(text).unwrap();
+            let written = ust.to_string();
+            assert_eq!(Ust::parse(&written).unwrap(), ust, "{}", written);
+        }
+
+        // Written in the declared encoding and read back through it
+        let path = temp("round_trip.ust");
+        for text in [MODE1, MODE2] {
+            let ust = Ust::parse(text).unwrap();
+            ust.write(&path).unwrap();
+            assert_eq!(Ust::read(&path).unwrap(), ust);
+        }
+
+        // Tempos keep all their decimals
+        let mut ust = Ust::parse(MODE1).unwrap();
+        (ust.settings.tempo, ust.notes[1].tempo) = (123.456, Some(97.125));
+        assert_eq!(Ust::parse(&ust.to_string()).unwrap(), ust);
+        assert!(ust.to_string().contains("Tempo=123.456\r\n"));
+        assert_eq!(tempo(120.0), "120.00");
+    }
+
+    #[test]
+    fn test_encoding() {
+        let mut ust = Ust::parse(MODE1).unwrap();
+        let bytes = ust.encode();
+        assert!(std::str::from_utf8(&bytes).is_err());
+        assert_eq!(encoding_rs::SHIFT_JIS.decode(&bytes).0, ust.to_string());
+
+        // Lyrics Shift-JIS can't hold switch the file to UTF-8
+        ust.notes[1].lyric = "가".to_string();
+        let text = String::from_utf8(ust.encode()).unwrap();
+        assert!(text.contains("Charset=UTF-8") && text.contains("Lyric=가"));
+
+        // Other declared charsets are written and read in that charset, not taken for Shift-JIS
+        let mut ust = Ust::parse(MODE1).unwrap();
+        ust.charset = Some("GBK".to_string());
+        ust.notes[1].lyric = "你".to_string();
+        let bytes = ust.encode();
+        assert_eq!(encoding_rs::GBK.decode(&bytes).0, ust.to_string());
+        let path = temp("gbk.ust");
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(Ust::read(&path).unwrap(), ust);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Ust::parse("[#SETTING]\nTempo=fast\n").is_err());
+        assert!(Ust::parse("[#SETTING]\nTempo=0\n").is_err());
+        let error = Ust::parse("[#0003]\nLength=480\nLyric=あ\n").unwrap_err();
+        assert_eq!(error.to_string(), "[#0003] NoteNum is missing");
+        assert!(Ust::parse("[#0000]\nLength=480\nNoteNum=60\nEnvelope=0,5\n").is_err());
+
+        // Plugin sections are skipped, and files before the version section still read
+        let ust = Ust::parse("[#SETTING]\nTempo=100\n[#PREV]\nLength=480\n[#0000]\nLength=480\nLyric=あ\nNoteNum=60\n[#NEXT]\n").unwrap();
+        assert_eq!((ust.version, ust.notes.len(), ust.settings.tempo), (None, 1, 100.0));
+    }
+}
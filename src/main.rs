@@ -3,29 +3,128 @@ mod features;
 mod parser;
 mod timing;
 mod flags;
+mod config;
+mod stretch;
+mod inspect;
+mod project;
 
 mod util;
 mod dsp;
 mod io;
 
 use std::env;
+use std::path::{Path, PathBuf};
 
+use config::Config;
+use features::{FeatureDatabase, Features, Progress};
 use resampler::Resampler;
-use parser::parse_args;
+use parser::{parse_args, parse_options};
 
 fn main() {
-    println!("straycrab {}", env!("CARGO_PKG_VERSION"));
+    // Keep stdout to JSON lines for frontends reading analysis progress
+    let json = env::args().any(|a| a == "--json");
+    let frq = env::args().any(|a| a == "--frq");
+    if !json {
+        println!("straycrab {}", env!("CARGO_PKG_VERSION"));
+    }
+
+    let mut config = Config::load().expect("Failed to load config");
+    let mut args: Vec<String> = env::args().filter(|a| a != "--json" && a != "--frq").collect();
+    parse_options(&mut args, &mut config).expect("Failed to parse options");
+
+    if args.get(1).map(|a| a.as_str()) == Some("flags") {
+        print!("{}", flags::help(&config.presets));
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("info") && args.len() == 3 {
+        let metadata = io::audio::read_metadata(Path::new(&args[2])).expect("Failed to read file");
+        print!("{}", metadata);
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("analyze") && args.len() == 3 {
+        let report = |progress: &Progress| match json {
+            true => println!("{}", serde_json::to_string(progress).unwrap()),
+            false => println!("{}", progress),
+        };
+        let dir = Path::new(&args[2]);
+        let config = config.for_path(dir).expect("Failed to load voicebank config");
+        let db = FeatureDatabase::generate(dir, &config, &report).expect("Failed to analyze voicebank");
+        if frq {
+            let written = db.write_frq(dir, &config).expect("Failed to write frequency maps");
+            if !json {
+                println!("Wrote {} frequency maps", written);
+            }
+        }
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("import-straycat") && args.len() == 3 {
+        let dir = Path::new(&args[2]);
+        let config = config.for_path(dir).expect("Failed to load voicebank config");
+        let (imported, skipped) = FeatureDatabase::import_straycat(dir, &config).expect("Failed to import straycat caches");
+        println!("Imported {} straycat caches, skipped {}", imported, skipped);
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("inspect") && args.len() == 3 {
+        let report = inspect::inspect(Path::new(&args[2])).expect("Failed to inspect feature file");
+        match json {
+            true => println!("{}", serde_json::to_string(&report).unwrap()),
+            false => println!("{}", report),
+        }
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("measure-quantization") && args.len() == 3 {
+        let dir = Path::new(&args[2]);
+        let db = FeatureDatabase::open(dir).expect("Failed to open feature database");
+        let features = db.keys().into_iter()
+            .map(|key| db.get(key).map(|f| f.unwrap()))
+            .collect::<Result<Vec<_>, _>>()
+            .expect("Failed to read feature database");
+        let measurements = io::quant::measure(&features, config.cache.compression_level).expect("Failed to measure quantization");
+        println!("{} samples", features.len());
+        for m in measurements {
+            println!("{}", m);
+        }
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("render-project") && (args.len() == 4 || args.len() == 6 && args[4] == "--voicebank") {
+        let voicebank = args.get(5).map(Path::new);
+        let notes = project::render(Path::new(&args[2]), Path::new(&args[3]), voicebank, &config).expect("Failed to render project");
+        println!("Rendered {} notes", notes);
+        return;
+    }
+
+    if args.get(1).map(|a| a.as_str()) == Some("export") && args.len() == 4 {
+        let sample = PathBuf::from(&args[2]);
+        let config = config.for_path(&sample).expect("Failed to load voicebank config");
+        let features = Features::load(&sample, &config, false).expect("Failed to get features");
+        io::npz::write(Path::new(&args[3]), &features).expect("Failed to write features");
+        return;
+    }
 
     // Check for the correct number of arguments
-    if env::args().len() < 14 {
-        println!("Usage: straycrab <input> <output> <pitch> <velocity> <flags> <offset> <length> <consonant> <cutoff> <volume> <modulation> <tempo> <pitchbend>");
+    if args.len() < 14 {
+        println!("Usage: straycrab <input> <output> <pitch> <velocity> <flags> <offset> <length> <consonant> <cutoff> <volume> <modulation> <tempo> <pitchbend> [--bit-depth 16|24|32] [--sample-rate <hz>] [--no-dither]");
+        println!("       straycrab flags");
+        println!("       straycrab info <rendered.wav>");
+        println!("       straycrab analyze <voicebank> [--json] [--frq]");
+        println!("       straycrab import-straycat <voicebank>");
+        println!("       straycrab export <sample.wav> <features.npz>");
+        println!("       straycrab measure-quantization <voicebank>");
+        println!("       straycrab inspect <features.scx> [--json]");
+        println!("       straycrab render-project <song.ust> <output.wav> [--voicebank <dir>]");
         return;
     }
 
-    let args: Vec<String> = env::args().collect();
-    let args = parse_args(&args).expect("Failed to parse arguments");
+    let args = parse_args(&args, &config).expect("Failed to parse arguments");
+    let config = config.for_path(&args.input).expect("Failed to load voicebank config");
 
-    let resampler = Resampler::new(&args);
+    let resampler = Resampler::new(&args, &config);
     resampler.render().expect("Failed to render");
 
     println!("Done!");
@@ -57,9 +156,10 @@ mod tests {
             "0".to_string(), // pitchbend
         ];
 
-        let args = parse_args(&args).expect("Failed to parse arguments");
+        let config = Config::default();
+        let args = parse_args(&args, &config).expect("Failed to parse arguments");
 
-        let resampler = Resampler::new(&args);
+        let resampler = Resampler::new(&args, &config);
         resampler.render().expect("Failed to render");
     }
 }
@@ -1,7 +1,8 @@
 use std::path::PathBuf;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 
+use crate::config::Config;
 use crate::flags::ResamplerFlags;
 use crate::util::misc::{note_to_midi, pitch_string_to_cents};
 
@@ -10,6 +11,9 @@ pub struct ResamplerInstruction {
     pub input: PathBuf,
     pub output: PathBuf,
     pub pitch: f64,
+    /// Pitch and flags as given on the command line, recorded in the output file
+    pub note: String,
+    pub flag_string: String,
     pub velocity: f32,
     pub flags: ResamplerFlags,
     pub offset: f32,
@@ -22,20 +26,52 @@ pub struct ResamplerInstruction {
     pub pitchbend: Vec<i16>,
 }
 
-pub fn parse_args(args: &Vec<String>) -> Result<ResamplerInstruction> {
+/// Strip `--option` arguments from `args`, applying them on top of `config`. These are not sent by UTAU,
+/// but let wrappers and scripts override the config file for a single render.
+pub fn parse_options(args: &mut Vec<String>, config: &mut Config) -> Result<()> {
+    let mut i = 0;
+    while i < args.len() {
+        let option = args[i].clone();
+        let mut value = || -> Result<String> {
+            if i + 1 >= args.len() {
+                return Err(anyhow!("Missing value for {}", option));
+            }
+            Ok(args.remove(i + 1))
+        };
+
+        match option.as_str() {
+            "--bit-depth" => config.output.bit_depth = value()?.parse()?,
+            "--sample-rate" => config.output.sample_rate = value()?.parse()?,
+            "--no-dither" => config.output.dither = false,
+            _ => {
+                i += 1;
+                continue;
+            }
+        }
+
+        args.remove(i);
+    }
+
+    config.validate()
+}
+
+pub fn parse_args(args: &Vec<String>, config: &Config) -> Result<ResamplerInstruction> {
     Ok(ResamplerInstruction {
         input: PathBuf::from(&args[1]),
         output: PathBuf::from(&args[2]),
-        pitch: note_to_midi(args[3].as_str()),
+        pitch: note_to_midi(args[3].as_str())?,
+        note: args[3].clone(),
+        flag_string: args[5].clone(),
         velocity: args[4].parse::<f32>()?,
-        flags: ResamplerFlags::parse(&args[5]),
+        flags: ResamplerFlags::parse_with_presets(&args[5], &config.presets),
         offset: args[6].parse::<f32>()?,
         length: args[7].parse::<usize>()?,
         consonant: args[8].parse::<f32>()?,
         cutoff: args[9].parse::<f32>()?,
         volume: args[10].parse::<f32>()?,
         modulation: (args[11].parse::<f64>()?) / 100.0,
-        tempo: args[12].parse::<f32>()?,
+        // UTAU passes the tempo as e.g. `!120`
+        tempo: args[12].trim_start_matches('!').parse::<f32>()?,
         pitchbend: pitch_string_to_cents(&args[13])?,
     })
 }
@@ -62,9 +98,24 @@ mod tests {
             "0.0".to_string(),
         ];
 
-        let resampler = parse_args(&args).unwrap();
+        let resampler = parse_args(&args, &Config::default()).unwrap();
         
         assert_eq!(resampler.input, PathBuf::from("input.wav"));
         assert_eq!(resampler.output, PathBuf::from("output.wav"));
     }
+
+    #[test]
+    fn test_parse_options() {
+        let mut args = vec!["straycrab", "in.wav", "--bit-depth", "24", "out.wav", "--sample-rate", "48000", "--no-dither", "-100"]
+            .into_iter().map(String::from).collect::<Vec<String>>();
+        let mut config = Config::default();
+
+        parse_options(&mut args, &mut config).unwrap();
+
+        assert_eq!(args, vec!["straycrab", "in.wav", "out.wav", "-100"]);
+        assert_eq!((config.output.bit_depth, config.output.sample_rate, config.output.dither), (24, 48000, false));
+
+        let mut args = vec!["straycrab".to_string(), "--bit-depth".to_string(), "12".to_string()];
+        assert!(parse_options(&mut args, &mut Config::default()).is_err());
+    }
 }
\ No newline at end of file
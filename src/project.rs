@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use rayon::prelude::*;
+
+use crate::config::Config;
+use crate::features::Features;
+use crate::flags::ResamplerFlags;
+use crate::io::audio::write_wav;
+use crate::io::oto::{Oto, OtoEntry};
+use crate::io::prefix_map::PrefixMap;
+use crate::io::ust::{CurveShape, Envelope, Note, PitchBend, Portamento, Ust, Vibrato, RESOLUTION};
+use crate::parser::ResamplerInstruction;
+use crate::resampler::Resampler;
+
+/// Ticks between the pitchbend values the resampler is given
+const PITCH_INTERVAL: f64 = 5.0;
+
+/// A note of a project as the resampler renders it and where it goes in the mix
+#[derive(Debug)]
+pub struct Segment {
+    /// Index of the note in the project
+    pub note: usize,
+    pub instruction: ResamplerInstruction,
+    /// ms from the start of the project to the start of the rendered note, its preutterance before the note
+    pub start: f64,
+    /// Rendered length in ms
+    pub duration: f64,
+    pub envelope: Envelope,
+}
+
+/// Render the project at `ust_path` into one track at `output`, with the voicebank at `voicebank` or else
+/// the project's `VoiceDir`. Returns how many notes were rendered.
+pub fn render(ust_path: &Path, output: &Path, voicebank: Option<&Path>, config: &Config) -> Result<usize> {
+    let ust = Ust::read(ust_path)?;
+    let voicebank = match voicebank {
+        Some(dir) => dir.to_path_buf(),
+        None => voice_dir(&ust, ust_path)?,
+    };
+    let config = config.for_path(&voicebank)?;
+
+    let oto = Oto::read(&voicebank)?;
+    let prefix_map = voicebank.join("prefix.map");
+    let prefix_map = prefix_map.is_file().then(|| PrefixMap::read(&prefix_map)).transpose()?;
+    let segments = plan(&ust, &voicebank, &oto, prefix_map.as_ref(), &config)?;
+
+    let mut files = segments.iter().map(|s| s.instruction.input.clone()).collect::<Vec<PathBuf>>();
+    files.sort();
+    files.dedup();
+    let features = files.iter().cloned().zip(Features::load_all(&files, &config)?).collect::<HashMap<PathBuf, Features>>();
+
+    let renders = segments.par_iter().map(|segment| {
+        let resampler = Resampler::new(&segment.instruction, &config);
+        let render = resampler.synthesize(features[&segment.instruction.input].clone())
+            .map_err(|e| anyhow!("Note {} ({}): {}", segment.note, ust.notes[segment.note].lyric, e))?;
+        Ok(render.map_or(vec![], |r| r.samples))
+    }).collect::<Result<Vec<Vec<f32>>>>()?;
+
+    let length = note_times(&ust).last().map_or(0.0, |(start, length, _)| start + length);
+    write_wav(output, mix(&segments, &renders, length, config.output.sample_rate), &config.output)?;
+
+    Ok(segments.len())
+}
+
+/// The project's `VoiceDir`, relative to the project's folder unless absolute. UTAU's `%VOICE%` folder
+/// can't be found outside of UTAU.
+fn voice_dir(ust: &Ust, ust_path: &Path) -> Result<PathBuf> {
+    let Some(dir) = &ust.settings.voice_dir else {
+        bail!("{:?} names no voicebank; pass --voicebank", ust_path);
+    };
+    if dir.contains("%VOICE%") {
+        bail!("{:?} uses a voicebank in UTAU's voice folder ({}); pass --voicebank", ust_path, dir);
+    }
+
+    Ok(ust_path.parent().unwrap_or(Path::new("")).join(dir.replace('\\', "/")))
+}
+
+/// Resampler instructions and mix positions of the notes of `ust`, leaving out rests
+pub fn plan(ust: &Ust, voicebank: &Path, oto: &Oto, prefix_map: Option<&PrefixMap>, config: &Config) -> Result<Vec<Segment>> {
+    let times = note_times(ust);
+    let notes = &ust.notes;
+
+    // Oto entry, preutterance and overlap of every note that isn't a rest
+    let mut placed: Vec<Option<(&OtoEntry, f64, f64)>> = vec![];
+    for (i, note) in notes.iter().enumerate() {
+        if note.is_rest() {
+            placed.push(None);
+            continue;
+        }
+
+        let entry = resolve(note, oto, prefix_map).ok_or_else(|| anyhow!("Note {} ({}): no oto entry for the lyric", i, note.lyric))?;
+        let stretch = consonant_stretch(note);
+        let mut pre = note.pre_utterance.unwrap_or(entry.preutterance) * stretch;
+        let mut overlap = note.voice_overlap.unwrap_or(entry.overlap) * stretch;
+
+        // Like UTAU, shrink both when the consonant would take more than half of the previous note or
+        // start before a previous rest
+        let room = match i.checked_sub(1) {
+            Some(prev) if notes[prev].is_rest() => times[prev].1,
+            Some(prev) => times[prev].1 / 2.0,
+            None => f64::INFINITY,
+        };
+        if pre - overlap > room {
+            let ratio = room / (pre - overlap);
+            pre *= ratio;
+            overlap *= ratio;
+        }
+        placed.push(Some((entry, pre, overlap)));
+    }
+
+    let mut segments = vec![];
+    for (i, note) in notes.iter().enumerate() {
+        let Some((entry, pre, _)) = placed[i] else {
+            continue;
+        };
+        let (start, length, tempo) = times[i];
+
+        // Render up to where the next note's overlap ends
+        let (next_pre, next_overlap) = match placed.get(i + 1) {
+            Some(Some((_, pre, overlap))) => (*pre, *overlap),
+            _ => (0.0, 0.0),
+        };
+        let consonant = entry.consonant * consonant_stretch(note);
+        let sustain = (length + pre - next_pre + next_overlap - consonant).max(0.0).ceil();
+
+        let flag_string = format!("{}{}", ust.settings.flags.as_deref().unwrap_or(""), note.flags.as_deref().unwrap_or(""));
+        let instruction = ResamplerInstruction {
+            input: voicebank.join(&entry.file),
+            output: PathBuf::new(),
+            pitch: note.note_num as f64,
+            note: String::new(),
+            velocity: note.velocity.unwrap_or(100.0) as f32,
+            flags: ResamplerFlags::parse_with_presets(&flag_string, &config.presets),
+            flag_string,
+            offset: (entry.offset + note.start_point.unwrap_or(0.0)) as f32,
+            length: sustain as usize,
+            consonant: entry.consonant as f32,
+            cutoff: entry.cutoff as f32,
+            volume: note.intensity.unwrap_or(100.0) as f32,
+            modulation: note.modulation.unwrap_or(0.0) / 100.0,
+            tempo: tempo as f32,
+            pitchbend: vec![],
+        };
+
+        let duration = consonant + sustain;
+        let step = PITCH_INTERVAL * tick_ms(tempo);
+        let pitchbend = (0..=(duration / step).ceil() as usize)
+            .map(|k| pitch_offset(note, ust.settings.mode2, tempo, length, k as f64 * step - pre).round().clamp(-32768.0, 32767.0) as i16)
+            .collect();
+
+        segments.push(Segment {
+            note: i,
+            instruction: ResamplerInstruction { pitchbend, ..instruction },
+            start: start - pre,
+            duration,
+            envelope: note.envelope.clone().unwrap_or_else(default_envelope),
+        });
+    }
+
+    Ok(segments)
+}
+
+/// Start, length in ms and tempo of every note, following tempo changes
+fn note_times(ust: &Ust) -> Vec<(f64, f64, f64)> {
+    let mut tempo = ust.settings.tempo;
+    let mut position = 0.0;
+
+    ust.notes.iter().map(|note| {
+        tempo = note.tempo.unwrap_or(tempo);
+        let (start, length) = (position, note.length as f64 * 60000.0 / (tempo * RESOLUTION as f64));
+        position += length;
+        (start, length, tempo)
+    }).collect()
+}
+
+fn tick_ms(tempo: f64) -> f64 {
+    60000.0 / (tempo * RESOLUTION as f64)
+}
+
+/// The oto entry of `note`'s lyric, with the prefix and suffix `prefix.map` gives its pitch when the
+/// voicebank has that alias
+fn resolve<'a>(note: &Note, oto: &'a Oto, prefix_map: Option<&PrefixMap>) -> Option<&'a OtoEntry> {
+    let mapped = prefix_map.and_then(|map| map.affixes(note.note_num as f64)).map(|(prefix, suffix)| format!("{}{}{}", prefix, note.lyric, suffix));
+
+    mapped.and_then(|alias| oto.get(&alias)).or_else(|| oto.get(&note.lyric))
+}
+
+/// UTAU's velocity rule, which the resampler applies to the consonant
+fn consonant_stretch(note: &Note) -> f64 {
+    2.0f64.powf((100.0 - note.velocity.unwrap_or(100.0)) / 100.0)
+}
+
+/// UTAU's envelope for notes that don't set one: a 5 ms fade in and a 35 ms fade out
+fn default_envelope() -> Envelope {
+    Envelope { p1: 0.0, p2: 5.0, p3: 35.0, v1: 0.0, v2: 100.0, v3: 100.0, v4: 0.0, p4: None, p5: None, v5: None }
+}
+
+/// Cents from the note's pitch at `x` ms from the note's start, from its Mode2 curve or Mode1 values, and
+/// its vibrato
+fn pitch_offset(note: &Note, mode2: bool, tempo: f64, length: f64, x: f64) -> f64 {
+    let bend = match (mode2, &note.portamento, &note.pitch_bend) {
+        (true, Some(portamento), _) => portamento_at(portamento, x),
+        (false, _, Some(pitch_bend)) => pitch_bend_at(pitch_bend, tempo, x),
+        _ => 0.0,
+    };
+
+    bend + note.vibrato.as_ref().map_or(0.0, |v| vibrato_at(v, length, x))
+}
+
+fn pitch_bend_at(pitch_bend: &PitchBend, tempo: f64, x: f64) -> f64 {
+    let cents = &pitch_bend.cents;
+    if cents.is_empty() {
+        return 0.0;
+    }
+
+    let interval = pitch_bend.interval.as_ref().and_then(|i| i.parse::<f64>().ok()).unwrap_or(PITCH_INTERVAL);
+    let position = ((x - pitch_bend.start.unwrap_or(0.0)) / (interval * tick_ms(tempo))).clamp(0.0, (cents.len() - 1) as f64);
+    let (i, frac) = (position.floor() as usize, position.fract());
+
+    cents[i] + (cents[(i + 1).min(cents.len() - 1)] - cents[i]) * frac
+}
+
+fn portamento_at(portamento: &Portamento, x: f64) -> f64 {
+    let mut points = vec![(portamento.start, portamento.start_pitch * 10.0)];
+    for (k, width) in portamento.widths.iter().enumerate() {
+        let t = points.last().unwrap().0 + width;
+        points.push((t, portamento.heights.get(k).copied().unwrap_or(0.0) * 10.0));
+    }
+
+    if x <= points[0].0 {
+        return points[0].1;
+    }
+    for (k, pair) in points.windows(2).enumerate() {
+        let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+        if x < x1 {
+            let s = (x - x0) / (x1 - x0);
+            let eased = match portamento.shapes.get(k).copied().unwrap_or_default() {
+                CurveShape::S => (1.0 - (PI * s).cos()) / 2.0,
+                CurveShape::Linear => s,
+                CurveShape::R => (s * PI / 2.0).sin(),
+                CurveShape::J => 1.0 - (s * PI / 2.0).cos(),
+            };
+            return y0 + (y1 - y0) * eased;
+        }
+    }
+
+    points.last().unwrap().1
+}
+
+/// Vibrato over the last `length` percent of a note of `note_length` ms
+fn vibrato_at(vibrato: &Vibrato, note_length: f64, x: f64) -> f64 {
+    let span = note_length * vibrato.length / 100.0;
+    let t = x - (note_length - span);
+    if span <= 0.0 || vibrato.cycle <= 0.0 || t < 0.0 || t > span {
+        return 0.0;
+    }
+
+    let fade = |share: f64, elapsed: f64| match share > 0.0 {
+        true => (elapsed / (span * share / 100.0)).min(1.0),
+        false => 1.0,
+    };
+    let depth = vibrato.depth * fade(vibrato.fade_in, t) * fade(vibrato.fade_out, span - t);
+
+    depth * ((2.0 * PI * (t / vibrato.cycle + vibrato.phase / 100.0)).sin() + vibrato.offset / 100.0)
+}
+
+/// Sum the rendered notes at their positions, shaped by their envelopes, into a track at least `length` ms long
+fn mix(segments: &[Segment], renders: &[Vec<f32>], length: f64, sample_rate: u32) -> Vec<f32> {
+    let at = |ms: f64| (ms * sample_rate as f64 / 1000.0).round() as i64;
+    let end = segments.iter().map(|s| at(s.start + s.duration)).fold(at(length), i64::max);
+    let mut track = vec![0.0f32; end.max(0) as usize];
+
+    for (segment, samples) in segments.iter().zip(renders) {
+        let duration = samples.len() as f64 * 1000.0 / sample_rate as f64;
+        let points = segment.envelope.points(duration);
+        let start = at(segment.start);
+
+        for (k, sample) in samples.iter().enumerate() {
+            // Notes starting before the project are cut
+            let Some(out) = usize::try_from(start + k as i64).ok().and_then(|i| track.get_mut(i)) else {
+                continue;
+            };
+            *out += sample * gain(&points, k as f64 * 1000.0 / sample_rate as f64) as f32;
+        }
+    }
+
+    track
+}
+
+/// Envelope volume at `t` ms, holding the first and last points outside of them
+fn gain(points: &[(f64, f64)], t: f64) -> f64 {
+    let Some(last) = points.iter().position(|(x, _)| *x > t) else {
+        return points.last().map_or(100.0, |p| p.1) / 100.0;
+    };
+    if last == 0 {
+        return points[0].1 / 100.0;
+    }
+
+    let ((x0, v0), (x1, v1)) = (points[last - 1], points[last]);
+    (v0 + (v1 - v0) * (t - x0) / (x1 - x0)) / 100.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::audio::OutputFormat;
+    use crate::util::testing::{harmonic_tone, temp};
+
+    const PROJECT: &str = "[#SETTING]\nTempo=120\nVoiceDir=bank\n\
+        [#0000]\nLength=480\nLyric=R\nNoteNum=60\n\
+        [#0001]\nLength=480\nLyric=あ\nNoteNum=60\nVelocity=100\n\
+        [#0002]\nLength=120\nLyric=か\nNoteNum=72\nIntensity=80\nFlags=B30\n\
+        [#0003]\nLength=480\nLyric=か\nNoteNum=60\nVelocity=0\nPreUtterance=100\n";
+
+    fn oto() -> Oto {
+        let text = "a.wav=あ,0,40,0,20,10\nka.wav=か,0,80,0,60,10\nka_hi.wav=か↑,5,80,0,60,10\n";
+        Oto { entries: Oto::parse(text, Path::new("")) }
+    }
+
+    #[test]
+    fn test_plan() {
+        let ust = Ust::parse(PROJECT).unwrap();
+        let prefix_map = PrefixMap::parse("C5\t\t↑\n");
+        let config = Config::default();
+        let segments = plan(&ust, Path::new("bank"), &oto(), Some(&prefix_map), &config).unwrap();
+
+        // The rest is left out, and notes start their preutterance early
+        assert_eq!(segments.iter().map(|s| s.note).collect::<Vec<usize>>(), [1, 2, 3]);
+        let a = &segments[0];
+        assert_eq!((a.instruction.input.clone(), a.start), (PathBuf::from("bank/a.wav"), 480.0));
+        // Through the next note's overlap: 500 ms + 20 ms preutterance - 60 + 10 of the next, 40 ms consonant
+        assert_eq!((a.instruction.length, a.duration), (430, 470.0));
+        assert!(a.instruction.pitchbend.iter().all(|c| *c == 0));
+
+        // prefix.map picks the alias for the note, and the project flags come first
+        let ka = &segments[1];
+        assert_eq!(ka.instruction.input, PathBuf::from("bank/ka_hi.wav"));
+        assert_eq!((ka.instruction.offset, ka.instruction.volume, ka.instruction.flag_string.as_str()), (5.0, 80.0, "B30"));
+        // Up to the end of the shrunk overlap of the next note: 125 + 60 - 69.4 + 6.9 ms, of which 80 ms
+        // consonant and the rest rounded up
+        assert_eq!((ka.instruction.length, ka.duration), (43, 123.0));
+
+        // Velocity 0 doubles preutterance, overlap and consonant, and since they take more than half of the
+        // 125 ms note before, they are shrunk to fit
+        let last = &segments[2];
+        assert_eq!(last.instruction.input, PathBuf::from("bank/ka.wav"));
+        let pre = 200.0 * 62.5 / 180.0;
+        assert!((last.start - (1125.0 - pre)).abs() < 1e-9, "{}", last.start);
+        // 500 + 69.4 ms with no note after it, of which the doubled 160 ms consonant and the rest rounded up
+        assert_eq!((last.instruction.length, last.duration), (410, 570.0));
+
+        let missing = Ust::parse("[#0000]\nLength=480\nLyric=さ\nNoteNum=60\n").unwrap();
+        assert!(plan(&missing, Path::new("bank"), &oto(), None, &config).is_err());
+    }
+
+    #[test]
+    fn test_pitch() {
+        // Mode2: from 2 semitones below over 100 ms, with vibrato over the last half of a 1000 ms note
+        let ust = Ust::parse("[#SETTING]\nMode2=True\n[#0000]\nLength=960\nLyric=あ\nNoteNum=60\nPBS=-50;-20\nPBW=100\nPBM=s\n\
+            VBR=50,100,30,0,0,0,0,0\n").unwrap();
+        let note = &ust.notes[0];
+        assert_eq!(pitch_offset(note, true, 120.0, 1000.0, -60.0), -200.0);
+        assert!((pitch_offset(note, true, 120.0, 1000.0, 0.0) + 100.0).abs() < 1e-9);
+        assert_eq!(pitch_offset(note, true, 120.0, 1000.0, 200.0), 0.0);
+        assert!((pitch_offset(note, true, 120.0, 1000.0, 525.0) - 30.0).abs() < 1e-9);
+
+        // Mode1 values every 5 ticks, which are 5.2083 ms at 120 BPM
+        let note = Ust::parse("[#0000]\nLength=480\nLyric=あ\nNoteNum=60\nPBType=5\nPBStart=-10\nPitchBend=-100,0,50\n").unwrap().notes[0].clone();
+        assert_eq!(pitch_offset(&note, false, 120.0, 500.0, -20.0), -100.0);
+        assert!((pitch_offset(&note, false, 120.0, 500.0, -10.0 + 1.5 * 5.0 * tick_ms(120.0)) - 25.0).abs() < 1e-9);
+        assert_eq!(pitch_offset(&note, true, 120.0, 500.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_mix() {
+        let envelope = |p1, p2, p3, v4| Envelope { p1, p2, p3, v1: 0.0, v2: 100.0, v3: 100.0, v4, p4: None, p5: None, v5: None };
+        let segment = |start, envelope| Segment { note: 0, instruction: ResamplerInstruction::default(), start, duration: 10.0, envelope };
+        let segments = [segment(-2.0, envelope(0.0, 0.0, 0.0, 100.0)), segment(5.0, envelope(0.0, 4.0, 4.0, 0.0))];
+
+        // At 1 kHz, one sample per ms
+        let track = mix(&segments, &[vec![1.0; 10], vec![1.0; 10]], 20.0, 1000);
+        assert_eq!(track.len(), 20);
+        // The first note is cut at the start of the project
+        assert_eq!(track[..5], [1.0; 5]);
+        // The second fades in over 4 ms on top of the end of the first, and out over its last 4 ms
+        assert_eq!(track[5..8], [1.0, 1.25, 1.5]);
+        assert_eq!(track[8..15], [0.75, 1.0, 1.0, 1.0, 0.75, 0.5, 0.25]);
+        assert_eq!(track[15..], [0.0; 5]);
+    }
+
+    #[test]
+    fn test_render_project() {
+        let root = temp("project");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("bank")).unwrap();
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        write_wav(&root.join("bank/a.wav"), tone, &OutputFormat::default()).unwrap();
+        std::fs::write(root.join("bank/oto.ini"), "a.wav=あ,20,60,-400,40,10\n").unwrap();
+
+        let text = "[#SETTING]\nTempo=120\nVoiceDir=bank\n[#0000]\nLength=240\nLyric=R\nNoteNum=57\n\
+            [#0001]\nLength=480\nLyric=あ\nNoteNum=57\n[#0002]\nLength=480\nLyric=あ\nNoteNum=59\n";
+        std::fs::write(root.join("song.ust"), text).unwrap();
+
+        let output = root.join("song.wav");
+        assert_eq!(render(&root.join("song.ust"), &output, None, &Config::default()).unwrap(), 2);
+
+        // 1250 ms of notes, with sound from the first preutterance on
+        let mut reader = hound::WavReader::open(&output).unwrap();
+        let samples = reader.samples::<f32>().map(Result::unwrap).collect::<Vec<f32>>();
+        assert_eq!(samples.len(), 55125);
+        assert!(samples.iter().all(|s| s.is_finite()));
+        let rms = |range: std::ops::Range<usize>| (samples[range.clone()].iter().map(|s| s * s).sum::<f32>() / range.len() as f32).sqrt();
+        assert_eq!(rms(0..8000), 0.0);
+        assert!(rms(13230..50000) > 0.01);
+
+        // The voicebank has to be found
+        assert!(render(&root.join("song.ust"), &output, Some(&root.join("missing")), &Config::default()).is_err());
+    }
+}
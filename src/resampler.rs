@@ -7,22 +7,33 @@ use ndarray::Array1;
 use num_traits::Pow;
 use num_traits::real::Real;
 
+use crate::config::Config;
+use crate::dsp::resample::resample;
 use crate::parser::ResamplerInstruction;
 use crate::features::Features;
-use crate::io::audio::{read_wav, write_wav};
+use crate::io::audio::{read_wav, write_metadata, write_wav, Cue, WavMetadata};
+use crate::stretch::{joins, stretch, StretchFrame};
 use crate::timing::TimingData;
-use crate::util::misc::{get_fft_size, mtof, smoothstep, DEFAULT_FS, F0_FLOOR, dump_test_data};
+use crate::util::misc::{mtof, smoothstep, F0_FLOOR};
 use crate::util::math::{linspace, Scalar};
 use crate::flags::ResamplerFlags;
 
 
+/// Synthesized audio, at the analysis rate until `synthesize` converts it, with named positions in seconds
+pub struct Render {
+    pub samples: Vec<f32>,
+    pub markers: Vec<(f64, String)>,
+}
+
 pub struct Resampler {
     pub in_file: PathBuf,
     pub out_file: PathBuf,
 
     pub pitch: f64,
+    pub note: String,
     pub velocity: f32,
     pub flags: ResamplerFlags,
+    pub flag_string: String,
 
     pub offset: f32,
     pub length: usize,
@@ -33,16 +44,19 @@ pub struct Resampler {
     pub tempo: f32,
     pub pitchbend: Vec<i16>,
 
+    pub config: Config,
 }
 
 impl Resampler {
-    pub fn new(instruction: &ResamplerInstruction) -> Resampler {
+    pub fn new(instruction: &ResamplerInstruction, config: &Config) -> Resampler {
         Resampler {
             in_file: instruction.input.clone(),
             out_file: instruction.output.clone(),
             pitch: instruction.pitch,
+            note: instruction.note.clone(),
             velocity: instruction.velocity,
             flags: instruction.flags,
+            flag_string: instruction.flag_string.clone(),
             offset: instruction.offset,
             length: instruction.length,
             consonant: instruction.consonant,
@@ -51,38 +65,61 @@ impl Resampler {
             modulation: instruction.modulation,
             tempo: instruction.tempo,
             pitchbend: instruction.pitchbend.clone(),
+            config: config.clone(),
         }
     }
 
     pub fn render(&self) -> Result<()> {
         let features = self.get_features()?;
-        let output = self.resample(features)?;
 
-        if let Some(output) = output {
-            write_wav(&self.out_file, output, 44100)?;
+        if let Some(output) = self.synthesize(features)? {
+            let format = &self.config.output;
+            write_wav(&self.out_file, output.samples, format)?;
+            write_metadata(&self.out_file, &self.metadata(&output.markers, format.sample_rate))?;
         }
 
         Ok(())
     }
 
-    fn get_features(&self) -> Result<Features> {
-        let path = self.in_file.with_extension("scx");
-        println!("{:?}", path);
+    /// Render `features` at the output sample rate without writing a file, or `None` for the `nul` output
+    pub fn synthesize(&self, features: Features) -> Result<Option<Render>> {
+        let sample_rate = features.sample_rate as u32;
+        let Some(output) = self.resample(features)? else {
+            return Ok(None);
+        };
 
-        if !self.flags.force_features {
-            return Features::generate(&self.in_file);
-        }
+        let format = &self.config.output;
+        let mut samples = resample(&output.samples, sample_rate, format.sample_rate);
+        samples.resize(self.output_length(format.sample_rate), 0.0);
 
-        if path.exists() {
-            let features = Features::from_file(&path)?;
+        Ok(Some(Render { samples, markers: output.markers }))
+    }
 
-            Ok(features)
-        } else {
-            Features::generate(&self.in_file)
+    /// Markers and provenance written into the rendered file
+    fn metadata(&self, markers: &[(f64, String)], sample_rate: u32) -> WavMetadata {
+        let cues = markers.iter().map(|(t, label)| Cue {
+            position: (t * sample_rate as f64).round() as u32,
+            label: label.clone(),
+        }).collect();
+
+        let alias = self.in_file.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+
+        WavMetadata {
+            cues,
+            info: vec![
+                (*b"ISFT", format!("straycrab {}", env!("CARGO_PKG_VERSION"))),
+                (*b"INAM", alias),
+                (*b"ICMT", format!("pitch={} flags={}", self.note, self.flag_string)),
+            ],
         }
     }
 
-    fn resample(&self, features: Features) -> Result<Option<Vec<f32>>> {
+    /// Use cached features unless the `G` flag forces regeneration
+    fn get_features(&self) -> Result<Features> {
+        Features::load(&self.in_file, &self.config, self.flags.force_features)
+    }
+
+    fn resample(&self, features: Features) -> Result<Option<Render>> {
         if self.out_file.to_str().unwrap() == "nul" {
             log::info!("Skipping resampling for {:?}: output file is Null", self.in_file);
             return Ok(None);
@@ -92,8 +129,10 @@ impl Resampler {
         let vol = self.volume / 100.0;
 
         log::info!("Decoding WORLD features");
-        let mut sp = rsworld::decode_spectral_envelope(&features.mgc, features.f0.len() as i32, DEFAULT_FS, get_fft_size());
-        let mut ap = rsworld::decode_aperiodicity(&features.bap, features.f0.len() as i32, DEFAULT_FS);
+        let fs = features.sample_rate;
+        let mut sp = rsworld::decode_spectral_envelope(&features.mgc, features.f0.len() as i32, fs, features.fft_size);
+        let mut ap = rsworld::decode_aperiodicity(&features.bap, features.f0.len() as i32, fs);
+        fill_non_finite(&mut ap);
 
         // Generate F0 offsets relative to base frequency
         let mut f0_off = vec![];
@@ -105,33 +144,18 @@ impl Resampler {
             }
         }
 
-        let timing = TimingData::calculate(features.f0.len(), self.offset, self.cutoff, self.consonant);
-
-        let t_render = self.interpolate_features(&mut sp, &mut ap, &mut f0_off, &timing)?;
-        let t = linspace(0.0, sp.len() as f64 * 0.005, sp.len());
-
+        let timing = TimingData::calculate(features.f0.len(), features.frame_period, self.offset, self.cutoff, self.consonant)?;
 
-        // Generate pitch parameters
-        let pitch: Vec<f64> = self.pitchbend.iter().map(|x| (*x as f64) / 100.0 + self.pitch).collect();
-
-        let mut pitch_render = if pitch.len() > 1 {
-            let mut t_pitch: Vec<f64> = vec![];
-            for i in 0..pitch.len() {
-                t_pitch.push((60.0 * i as f64) / (self.tempo as f64 * 96.0));
-            }
-
-            let mut pitch_render = vec![];
-            let pitch_points = vec_to_points(&t_pitch, &pitch);
-            let pitch_interpolator = Spline::from_vec(pitch_points);
-            for _t in t.iter() {
-                pitch_render.push(pitch_interpolator.sample(*_t));
-            }
-
-            pitch_render
-        } else {
-            vec![pitch[0]; t.len()]
-        };
+        let mut markers = vec![];
+        let t_render = self.interpolate_features(&mut sp, &mut ap, &mut f0_off, &timing, &mut markers)?;
+        if t_render.is_empty() {
+            log::warn!("Nothing to render for {:?}, writing silence", self.in_file);
+            return Ok(Some(Render { samples: vec![0.0; self.output_length(fs as u32)], markers }));
+        }
+        // Output time of every rendered frame; frame i is synthesized at i * frame_period
+        let t = (0..sp.len()).map(|i| i as f64 * features.frame_period / 1000.0).collect::<Vec<f64>>();
 
+        let mut pitch_render = self.pitch_curve(&t);
 
         // Check if flags has the PitchOffset flag, if so, apply its value to the pitch
         if let Some(offset) = self.flags.pitch_offset {
@@ -145,71 +169,117 @@ impl Resampler {
             f0.push(mtof(pitch_render[i]) + f0_off[i] * self.modulation);
         }
 
-        println!("still alive!");
-
         // Process pre-render flags
         // self.process_prerender_flags(&mut sp, &mut ap, &mut f0, &t, &timing)?;
 
-        println!("Processed pre-render flags");
-
         // Yass, slay, synthesize
-        println!("Synthesizing with f0: {}, sp: {}x{}, ap: {}x{} (t: {})", f0.len(), sp.len(), sp[0].len(), ap.len(), ap[0].len(), t_render.len());
+        log::debug!("Synthesizing with f0: {}, sp: {}x{}, ap: {}x{} (t: {})", f0.len(), sp.len(), sp[0].len(), ap.len(), ap[0].len(), t_render.len());
+        let render = rsworld::synthesis(&f0, &sp, &ap, features.frame_period, fs);
+
+        // Apply post-render flags
+        // TODO: Implement post-render flags
 
-        dump_test_data(&sp, &ap, &f0, &t_render, "test/data.txt");
 
-        let render = rsworld::synthesis(&f0, &sp, &ap, 5.0, DEFAULT_FS);
+        let mut render = render.iter().map(|x| *x as f32 * vol).collect::<Vec<f32>>();
 
-        println!("Synthesized");
+        // Pad or trim so the file is exactly the rendered consonant plus the requested length
+        render.resize(self.output_length(fs as u32), 0.0);
 
-        // Apply post-render flags
-        // TODO: Implement post-render flags
+        Ok(Some(Render { samples: render, markers }))
+    }
+
+    /// Number of output samples: the velocity-scaled consonant followed by `length` ms
+    fn output_length(&self, sample_rate: u32) -> usize {
+        ((self.consonant_length() + self.length as f64 / 1000.0) * sample_rate as f64).round() as usize
+    }
 
+    /// MIDI pitch at output times `t` (in seconds). Following UTAU and OpenUtau, the first pitchbend point
+    /// lies at the start of the rendered file (the note position minus the velocity-scaled preutterance),
+    /// and points are spaced 5 ticks apart at 480 ticks per quarter note.
+    fn pitch_curve(&self, t: &[f64]) -> Vec<f64> {
+        let pitch: Vec<f64> = self.pitchbend.iter().map(|x| (*x as f64) / 100.0 + self.pitch).collect();
+
+        if pitch.len() < 2 {
+            return vec![pitch.first().cloned().unwrap_or(self.pitch); t.len()];
+        }
 
-        let render = render.iter().map(|x| *x as f32).collect::<Vec<f32>>();
-        Ok(Some(render))
+        let t_pitch = (0..pitch.len()).map(|i| (60.0 * i as f64) / (self.tempo as f64 * 96.0)).collect::<Vec<f64>>();
+        let t_last = *t_pitch.last().unwrap();
+
+        // Hold the first and last pitch outside of the curve instead of extrapolating
+        let pitch_interpolator = Spline::from_vec(vec_to_points(&t_pitch, &pitch));
+        t.iter().map(|t| pitch_interpolator.sample(t.clamp(0.0, t_last))).collect()
     }
     
 
-    fn interpolate_features(&self, sp: &mut Vec<Vec<f64>>, ap: &mut Vec<Vec<f64>>, f0_off: &mut Vec<f64>, timing: &TimingData) -> Result<Vec<f64>> {
+    /// Rendered consonant length in seconds, following UTAU's velocity rule:
+    /// the consonant is scaled by 2^((100 - velocity) / 100), so velocity 100 keeps it unchanged.
+    fn consonant_length(&self) -> f64 {
+        let vel = 2.0f64.powf((100.0 - self.velocity as f64) / 100.0);
+        vel * self.consonant as f64 / 1000.0
+    }
+
+    /// Re-time the features to the rendered frames. `markers` receives the output times of the consonant end
+    /// and of the seams in the stretched region.
+    fn interpolate_features(&self, sp: &mut Vec<Vec<f64>>, ap: &mut Vec<Vec<f64>>, f0_off: &mut Vec<f64>, timing: &TimingData, markers: &mut Vec<(f64, String)>) -> Result<Vec<f64>> {
         let f0_off_interpolator = CubicSmoothingSpline::new(&timing.positions, &f0_off).make().unwrap();
 
-        let vel = (1.0 - self.velocity / 100.0).powf(2.0);
-        let length_req = self.length as f32 / 1000.0;
-        let stretch_length = timing.end - timing.con;
+        let fps = timing.frame_rate();
+        let length_req = self.length as f64 / 1000.0;
+        let mode = self.flags.stretch_mode.unwrap_or(self.config.stretch.mode);
+
+        // Generate timing vectors for consonant and stretch areas. The consonant end is excluded,
+        // since it is the first frame of the stretch area.
+        let n_consonant = (self.consonant_length() * fps).round() as usize;
+        let f_consonant = (0..n_consonant)
+            .map(|i| StretchFrame::at(timing.start as f64 + (timing.con - timing.start) as f64 * i as f64 / n_consonant as f64))
+            .collect::<Vec<StretchFrame>>();
+        let f_stretch = stretch(mode, sp, timing, length_req, &self.config.stretch, self.length as u64);
+
+        markers.push((n_consonant as f64 / fps, "Consonant end".to_string()));
+        for (i, k) in joins(&f_stretch).into_iter().enumerate() {
+            markers.push(((n_consonant + k) as f64 / fps, format!("Stretch join {}", i + 1)));
+        }
 
-        // Generate timing vectors for consonant and stretch areas
-        let t_consonant = linspace(timing.start as f64, timing.con as f64, (vel * self.consonant / 5.0) as usize);
-        let t_stretch = if stretch_length > length_req {
-            let con_idx = (200.0 * timing.con) as usize;
-            let len_idx = (200.0 * length_req) as usize;
+        // Timing is kept in f32, so allow for rounding at the end of the sample
+        let last = *timing.positions.last().unwrap();
+        let frames = [f_consonant, f_stretch].concat().into_iter().filter(|f| f.t >= 0.0 && f.t <= last + 1e-6).collect::<Vec<StretchFrame>>();
+        if frames.is_empty() {
+            sp.clear();
+            ap.clear();
+            f0_off.clear();
+            return Ok(vec![]);
+        }
 
-            timing.positions[con_idx..con_idx+len_idx].to_vec()
-        } else {
-            linspace(timing.con as f64, timing.end as f64, (200.0 * length_req) as usize)
-        };
+        let t_render = frames.iter().map(|f| f.t.min(last)).collect::<Vec<f64>>();
+        let t_alt = frames.iter().map(|f| f.t_alt.clamp(0.0, last)).collect::<Vec<f64>>();
+
+        let blend = |a: f64, b: f64, mix: f64| a * (1.0 - mix) + b * mix;
 
-        let t_render = [t_consonant, t_stretch].concat().iter().filter(|x| **x >= 0.0 && **x <= *timing.positions.last().unwrap()).cloned().collect::<Vec<f64>>();
         let mut new_sp = vec![vec![0.0; sp[0].len()]; t_render.len()];
         let mut new_ap = vec![vec![0.0; ap[0].len()]; t_render.len()];
         
         for i in 0..sp[0].len() {
-            println!("Interpolating feature {}: x {} y {}-{}", i, timing.positions.len(), sp.len(), sp[0].len());
+            log::trace!("Interpolating feature {}: x {} y {}-{}", i, timing.positions.len(), sp.len(), sp[0].len());
             let sp_points = vec_to_points(&timing.positions, &sp.iter().map(|x| x[i]).collect::<Vec<f64>>());
             let ap_points = vec_to_points(&timing.positions, &ap.iter().map(|x| x[i]).collect::<Vec<f64>>());
 
             let sp_interpolator = Spline::from_vec(sp_points);
             let ap_interpolator = Spline::from_vec(ap_points);
 
-            for (j, t) in t_render.iter().enumerate() {
-                new_sp[j][i] = sp_interpolator.sample(*t);
-                new_ap[j][i] = ap_interpolator.sample(*t).clamp(0.0, 1.0);
+            for (j, f) in frames.iter().enumerate() {
+                let (t, t_alt) = (t_render[j], t_alt[j]);
+                new_sp[j][i] = blend(sp_interpolator.sample(t), sp_interpolator.sample(t_alt), f.mix);
+                new_ap[j][i] = blend(ap_interpolator.sample(t), ap_interpolator.sample(t_alt), f.mix).clamp(0.0, 1.0);
             }
         }
 
         *sp = new_sp;
         *ap = new_ap;
         
-        *f0_off = f0_off_interpolator.evaluate(&t_render)?.to_vec();
+        let f0_a = f0_off_interpolator.evaluate(&t_render)?;
+        let f0_b = f0_off_interpolator.evaluate(&t_alt)?;
+        *f0_off = frames.iter().enumerate().map(|(j, f)| blend(f0_a[j], f0_b[j], f.mix)).collect();
 
         Ok(t_render)
     }
@@ -230,8 +300,8 @@ impl Resampler {
 
         // Process Gender flag
         if let Some(gender) = self.flags.gender {
-            let freq_x = linspace(0.0, 1.0, (get_fft_size() as f32 / 2.0).floor() as usize + 1);
-            let freq_x2 = linspace(0.0, gender, (get_fft_size() as f32 / 2.0).floor() as usize + 1).iter().map(|x| x.max(0.0).min(1.0)).collect::<Vec<f64>>();
+            let freq_x = linspace(0.0, 1.0, sp[0].len());
+            let freq_x2 = linspace(0.0, gender, sp[0].len()).iter().map(|x| x.max(0.0).min(1.0)).collect::<Vec<f64>>();
 
             let mut new_sp = vec![vec![0.0; sp[0].len()]; t.len()];
 
@@ -249,4 +319,298 @@ impl Resampler {
 
         Ok(())
     }
+}
+
+/// Make NaN and infinite aperiodicity fully aperiodic. D4C can leave them in bands of synthetic or silent
+/// frames, and they would otherwise spread through the interpolation into neighbouring frames.
+fn fill_non_finite(ap: &mut [Vec<f64>]) {
+    for a in ap.iter_mut().flatten().filter(|a| !a.is_finite()) {
+        *a = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::Quality;
+    use crate::stretch::StretchMode;
+    use crate::util::misc::DEFAULT_FS;
+    use crate::util::testing::{harmonic_tone, temp};
+    use proptest::prelude::*;
+
+    fn resampler(velocity: f32, volume: f32) -> Resampler {
+        Resampler::new(&ResamplerInstruction {
+            pitch: 60.0,
+            velocity,
+            consonant: 100.0,
+            offset: 100.0,
+            length: 500,
+            volume,
+            tempo: 120.0,
+            pitchbend: vec![0],
+            ..Default::default()
+        }, &Config::default())
+    }
+
+    fn consonant_frames(velocity: f32, frame_period: f64) -> usize {
+        let n = (1000.0 / frame_period) as usize;
+        let mut sp = vec![vec![1.0; 8]; n];
+        let mut ap = vec![vec![0.5; 8]; n];
+        let mut f0_off = vec![0.0; n];
+        let timing = TimingData::calculate(n, frame_period, 100.0, 0.0, 100.0).unwrap();
+
+        let t_render = resampler(velocity, 100.0).interpolate_features(&mut sp, &mut ap, &mut f0_off, &timing, &mut vec![]).unwrap();
+        t_render.iter().filter(|t| **t < timing.con as f64 - 1e-6).count()
+    }
+
+    /// One second of a flat, voiced 220 Hz sample, encoded the same way `Features::generate` does
+    fn flat_features(frame_period: f64) -> Features {
+        let n = (1000.0 / frame_period) as usize;
+        let analysis = Config::default().analysis;
+        let fft_size = analysis.fft_size(DEFAULT_FS);
+        let sp = vec![vec![1e-4; fft_size as usize / 2 + 1]; n];
+        let ap = vec![vec![0.01; fft_size as usize / 2 + 1]; n];
+
+        Features::new(
+            220.0,
+            frame_period,
+            DEFAULT_FS,
+            fft_size,
+            vec![220.0; n],
+            rsworld::code_spectral_envelope(&sp, n as i32, DEFAULT_FS, fft_size, analysis.mgc_dims()),
+            rsworld::code_aperiodicity(&ap, n as i32, DEFAULT_FS),
+        )
+    }
+
+    #[test]
+    fn test_consonant_velocity() {
+        // 100 ms consonant at 5 ms per frame
+        assert_eq!(consonant_frames(100.0, 5.0), 20);
+        assert_eq!(consonant_frames(0.0, 5.0), 40);
+        assert_eq!(consonant_frames(200.0, 5.0), 10);
+    }
+
+    #[test]
+    fn test_frame_period() {
+        assert_eq!(consonant_frames(100.0, 2.0), 50);
+        assert_eq!(consonant_frames(100.0, 10.0), 10);
+
+        // The rendered duration does not depend on the frame period
+        let render = |frame_period: f64| resampler(100.0, 100.0).resample(flat_features(frame_period)).unwrap().unwrap().samples.len();
+        let (fine, coarse) = (render(2.0), render(10.0));
+        assert!((fine as f64 - coarse as f64).abs() / (DEFAULT_FS as f64) < 0.011, "{} vs {} samples", fine, coarse);
+    }
+
+    #[test]
+    fn test_fill_non_finite() {
+        let mut ap = vec![vec![0.5, f64::NAN], vec![f64::INFINITY, 0.001]];
+        fill_non_finite(&mut ap);
+        assert_eq!(ap, [[0.5, 1.0], [1.0, 0.001]]);
+
+        // A NaN band in one frame leaves the render of a single note finite
+        let mut features = flat_features(5.0);
+        features.bap[100] = vec![f64::NAN; features.bap[100].len()];
+        let samples = resampler(100.0, 100.0).resample(features).unwrap().unwrap().samples;
+        assert!(samples.iter().all(|s| s.is_finite()));
+    }
+
+    #[test]
+    fn test_envelope_fft_size() {
+        // The envelope is decoded with the FFT size it was analyzed with, whatever the configured one
+        let render = |quality: Quality| {
+            let mut resampler = resampler(100.0, 100.0);
+            resampler.config.analysis.quality = quality;
+            resampler.resample(flat_features(5.0)).unwrap().unwrap().samples
+        };
+        assert_eq!(render(Quality::Standard), render(Quality::High));
+    }
+
+    #[test]
+    fn test_stretch_modes_render_requested_length() {
+        let render = |flags: &str| {
+            let mut resampler = resampler(100.0, 100.0);
+            resampler.length = 2000;
+            resampler.flags = ResamplerFlags::parse(flags);
+            resampler.resample(flat_features(5.0)).unwrap().unwrap().samples.len()
+        };
+
+        let interpolated = render("st0");
+        assert_eq!(render("st1"), interpolated);
+        assert_eq!(render("st2"), interpolated);
+        assert_eq!(render("st3"), interpolated);
+    }
+
+    #[test]
+    fn test_output_length_across_tempos() {
+        for tempo in [60.0, 120.0, 187.5] {
+            for length in [120, 500, 1750] {
+                let mut resampler = resampler(100.0, 100.0);
+                resampler.tempo = tempo;
+                resampler.length = length;
+                resampler.pitchbend = (0..40).map(|i| (i * 10) as i16).collect();
+
+                let render = resampler.resample(flat_features(5.0)).unwrap().unwrap().samples;
+
+                // 100 ms consonant plus the requested length, to the sample
+                let expected = (0.1 + length as f64 / 1000.0) * DEFAULT_FS as f64;
+                assert_eq!(render.len(), expected.round() as usize, "tempo {}, length {}", tempo, length);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pitch_curve_alignment() {
+        let mut resampler = resampler(100.0, 100.0);
+        resampler.tempo = 125.0;
+        resampler.pitchbend = vec![0, 100, 200, 300];
+
+        // At 125 BPM, pitchbend points are 5 ticks = 5 ms apart, starting at the start of the file
+        let pitch = resampler.pitch_curve(&[0.0, 0.005, 0.010, 0.015, 0.5]);
+        for (p, expected) in pitch.iter().zip([60.0, 61.0, 62.0, 63.0, 63.0]) {
+            assert!((p - expected).abs() < 1e-9, "{} != {}", p, expected);
+        }
+    }
+
+    #[test]
+    fn test_empty_render_is_silent() {
+        let mut resampler = resampler(100.0, 100.0);
+        resampler.consonant = 0.0;
+        resampler.length = 0;
+
+        assert_eq!(resampler.resample(flat_features(5.0)).unwrap().unwrap().samples, Vec::<f32>::new());
+    }
+
+    #[test]
+    fn test_feature_cache_policy() {
+        let path = temp("cache_policy.wav");
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        crate::io::audio::write_wav(&path, tone, &Default::default()).unwrap();
+        let _ = std::fs::remove_file(path.with_extension("scx"));
+
+        let mut resampler = resampler(100.0, 100.0);
+        resampler.in_file = path.clone();
+        let generated = resampler.get_features().unwrap();
+
+        // Mark the cache, keeping its header, to see whether it is used
+        let (header, _) = crate::io::scx::read(&path.with_extension("scx")).unwrap();
+        let marked = Features { base: 1.0, ..generated.clone() };
+        marked.to_file(&path.with_extension("scx"), &header.unwrap(), &crate::io::scx::CacheConfig::default()).unwrap();
+
+        assert_eq!(resampler.get_features().unwrap().base, 1.0);
+
+        // G regenerates and rewrites the cache
+        resampler.flags.force_features = true;
+        assert!((resampler.get_features().unwrap().base - 220.0).abs() < 5.0);
+        resampler.flags.force_features = false;
+        assert!((resampler.get_features().unwrap().base - 220.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_voicebank_database() {
+        let root = temp("db_bank");
+        let _ = std::fs::remove_dir_all(&root);
+        let path = root.join("C4").join("a.wav");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let tone = harmonic_tone(220.0, 44100, 22050);
+        crate::io::audio::write_wav(&path, tone, &Default::default()).unwrap();
+        let db_path = root.join("db_bank.scx");
+        crate::features::FeatureDatabase::new(Default::default()).to_file(&db_path, &crate::io::scx::CacheConfig::default()).unwrap();
+
+        let mut resampler = resampler(100.0, 100.0);
+        resampler.in_file = path.clone();
+
+        // Analyzed into a per-file cache, leaving the database to `straycrab analyze`
+        let generated = resampler.get_features().unwrap();
+        assert!(path.with_extension("scx").exists());
+        let (mut db, _) = crate::features::FeatureDatabase::find(&path).unwrap();
+        assert!(db.entry("C4/a.wav").unwrap().is_none());
+
+        // Mark an entry like `analyze` would add, to see whether it is used once the per-file cache is gone
+        let entry = crate::features::DatabaseEntry::analyze(&path, &resampler.config).unwrap();
+        db.insert("C4/a.wav".to_string(), crate::features::DatabaseEntry { features: Features { base: 1.0, ..generated }, ..entry });
+        db.to_file(&db_path, &crate::io::scx::CacheConfig::default()).unwrap();
+        std::fs::remove_file(path.with_extension("scx")).unwrap();
+        assert_eq!(resampler.get_features().unwrap().base, 1.0);
+
+        // G reanalyzes, and the new per-file cache takes precedence over the entry
+        resampler.flags.force_features = true;
+        assert!((resampler.get_features().unwrap().base - 220.0).abs() < 5.0);
+        resampler.flags.force_features = false;
+        assert!((resampler.get_features().unwrap().base - 220.0).abs() < 5.0);
+    }
+
+    #[test]
+    fn test_markers_and_provenance() {
+        let mut resampler = resampler(100.0, 100.0);
+        resampler.length = 2000;
+        resampler.flags.stretch_mode = Some(StretchMode::Loop);
+        resampler.note = "C4".to_string();
+        resampler.flag_string = "st1B50".to_string();
+        resampler.in_file = PathBuf::from("voice/_あ.wav");
+        resampler.out_file = PathBuf::from("out.wav");
+
+        let markers = resampler.resample(flat_features(5.0)).unwrap().unwrap().markers;
+
+        // The 800 ms region has to loop to fill two seconds
+        assert_eq!(markers[0], (0.1, "Consonant end".to_string()));
+        assert!(markers.len() > 2);
+        assert!(markers.windows(2).all(|w| w[0].0 < w[1].0 && w[1].0 < 2.1));
+
+        let metadata = resampler.metadata(&markers, 48000);
+        assert_eq!(metadata.cues[0], Cue { position: 4800, label: "Consonant end".to_string() });
+        assert_eq!(metadata.info[1], (*b"INAM", "_あ".to_string()));
+        assert_eq!(metadata.info[2], (*b"ICMT", "pitch=C4 flags=st1B50".to_string()));
+    }
+
+    #[test]
+    fn test_volume_and_consonant_in_output() {
+        let features = flat_features(5.0);
+
+        let render = |velocity: f32, volume: f32| {
+            let mut resampler = resampler(velocity, volume);
+            resampler.out_file = PathBuf::from("out.wav");
+            resampler.resample(features.clone()).unwrap().unwrap().samples
+        };
+
+        let full = render(100.0, 100.0);
+        let half = render(100.0, 50.0);
+        let slow = render(0.0, 100.0);
+
+        // The aperiodic component is noise, so compare energy rather than samples
+        let rms = |x: &Vec<f32>| (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32).sqrt();
+        assert_eq!(full.len(), half.len());
+        let ratio = rms(&half) / rms(&full);
+        assert!((ratio - 0.5).abs() < 0.01, "volume ratio {} (rms {} / {})", ratio, rms(&half), rms(&full));
+
+        // Velocity 0 doubles the 100 ms consonant, which adds 100 ms to the output
+        assert_eq!(slow.len() - full.len(), DEFAULT_FS as usize / 10);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_oto_values_never_panic(
+            offset in -200.0f32..1500.0,
+            cutoff in -1500.0f32..1500.0,
+            consonant in -200.0f32..1500.0,
+            velocity in 0.0f32..200.0,
+            length in 0usize..3000,
+            mode in 0i32..4,
+        ) {
+            let n = 200;
+            let mut sp = vec![vec![1.0; 4]; n];
+            let mut ap = vec![vec![0.5; 4]; n];
+            let mut f0_off = vec![0.0; n];
+
+            let mut resampler = resampler(velocity, 100.0);
+            resampler.consonant = consonant;
+            resampler.length = length;
+            resampler.flags.stretch_mode = StretchMode::from_flag(mode);
+
+            if let Ok(timing) = TimingData::calculate(n, 5.0, offset, cutoff, consonant) {
+                resampler.interpolate_features(&mut sp, &mut ap, &mut f0_off, &timing, &mut vec![]).unwrap();
+            }
+        }
+    }
 }
\ No newline at end of file
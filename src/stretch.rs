@@ -0,0 +1,325 @@
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+
+use crate::timing::TimingData;
+use crate::util::math::linspace;
+
+#[derive(Debug, Copy, Clone, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StretchMode {
+    /// Re-time the whole consonant-to-cutoff region to the requested length
+    #[default]
+    Interpolate,
+    /// Play through once, then loop the stable region forwards with a crossfade at the seam
+    Loop,
+    /// Play through once, then bounce back and forth over the stable region
+    PingPong,
+    /// Play through once, then concatenate random crossfaded segments of the stable region
+    Random,
+}
+
+impl StretchMode {
+    /// Mode for the numeric value of the `st` flag
+    pub fn from_flag(value: i32) -> Option<StretchMode> {
+        match value {
+            0 => Some(StretchMode::Interpolate),
+            1 => Some(StretchMode::Loop),
+            2 => Some(StretchMode::PingPong),
+            3 => Some(StretchMode::Random),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(default)]
+pub struct StretchConfig {
+    pub mode: StretchMode,
+    /// Crossfade length at loop seams and segment joins, in ms
+    pub crossfade: f64,
+    /// Segment length for the random mode, in ms
+    pub segment: f64,
+}
+
+impl Default for StretchConfig {
+    fn default() -> Self {
+        StretchConfig {
+            mode: StretchMode::Interpolate,
+            crossfade: 30.0,
+            segment: 120.0,
+        }
+    }
+}
+
+/// A rendered frame, blended from the features at `t` and `t_alt` as `(1 - mix) * t + mix * t_alt`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StretchFrame {
+    pub t: f64,
+    pub t_alt: f64,
+    pub mix: f64,
+}
+
+impl StretchFrame {
+    pub fn at(t: f64) -> StretchFrame {
+        StretchFrame { t, t_alt: t, mix: 0.0 }
+    }
+}
+
+/// Generate the frames covering `length` seconds of the sustained region, starting at the consonant end.
+/// `sp` is the spectral envelope on the `timing` grid and is used to find the stable region.
+pub fn stretch(mode: StretchMode, sp: &[Vec<f64>], timing: &TimingData, length: f64, config: &StretchConfig, seed: u64) -> Vec<StretchFrame> {
+    let fps = timing.frame_rate();
+    let n = (fps * length) as usize;
+    let (con, end) = (timing.con as f64, timing.end as f64);
+
+    if mode == StretchMode::Interpolate || end - con >= length {
+        return interpolate(timing, length);
+    }
+
+    let (ls, le) = stable_region(sp, timing, 2.0 * config.crossfade / 1000.0);
+    let loop_len = le - ls;
+    if loop_len * fps < 2.0 {
+        log::warn!("Stable region too short to loop, falling back to interpolation");
+        return interpolate(timing, length);
+    }
+
+    let xfade = (config.crossfade / 1000.0).min(loop_len / 2.0);
+    let segment = (config.segment / 1000.0).clamp(2.0 * xfade, loop_len);
+    let hop = segment - xfade;
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut starts: Vec<f64> = vec![];
+
+    (0..n).map(|k| {
+        let u = con + k as f64 / fps;
+
+        match mode {
+            StretchMode::Loop => {
+                let p = if u < le { u } else { ls + (u - le) % loop_len };
+
+                if xfade > 0.0 && p >= le - xfade {
+                    // Fade towards the material right before the loop start, so the jump back is seamless
+                    StretchFrame { t: p, t_alt: (p - loop_len).max(con), mix: (p - (le - xfade)) / xfade }
+                } else {
+                    StretchFrame::at(p)
+                }
+            }
+            StretchMode::PingPong => {
+                if u < le {
+                    StretchFrame::at(u)
+                } else {
+                    let q = (u - le) % (2.0 * loop_len);
+                    StretchFrame::at(if q < loop_len { le - q } else { ls + q - loop_len })
+                }
+            }
+            StretchMode::Random => {
+                if u < le - xfade {
+                    return StretchFrame::at(u);
+                }
+
+                // Segment j starts at le - xfade + (j - 1) * hop; segment 0 is the play-through up to le
+                let j = ((u - (le - xfade)) / hop) as usize + 1;
+                // A segment can span the whole stable region, where rounding may put its last start before `ls`
+                while starts.len() < j {
+                    starts.push(rng.gen_range(ls..=(le - segment).max(ls)));
+                }
+
+                let onset = |j: usize| le - xfade + (j - 1) as f64 * hop;
+                let o = u - onset(j);
+                let current = starts[j - 1] + o;
+
+                if o < xfade {
+                    let previous = if j == 1 { u } else { starts[j - 2] + u - onset(j - 1) };
+                    StretchFrame { t: previous, t_alt: current, mix: o / xfade }
+                } else {
+                    StretchFrame::at(current)
+                }
+            }
+            StretchMode::Interpolate => unreachable!(),
+        }
+    }).collect()
+}
+
+/// Indices of the frames where playback stops following the recording: a crossfade into other material
+/// starts, or playback jumps or turns around without one. These are the seams of loops and segments.
+pub fn joins(frames: &[StretchFrame]) -> Vec<usize> {
+    let mut res = vec![];
+    let fading = |f: &StretchFrame| f.t_alt != f.t;
+
+    for k in 1..frames.len() {
+        let (a, b) = (&frames[k - 1], &frames[k]);
+
+        if fading(b) && !fading(a) {
+            res.push(k);
+        } else if k >= 2 && !frames[k - 2..=k].iter().any(fading) {
+            let (before, after) = (a.t - frames[k - 2].t, b.t - a.t);
+            if after < 0.0 && before >= 0.0 || after > 0.0 && before < 0.0 {
+                res.push(k);
+            }
+        }
+    }
+
+    res
+}
+
+fn interpolate(timing: &TimingData, length: f64) -> Vec<StretchFrame> {
+    let fps = timing.frame_rate();
+    let stretch_length = (timing.end - timing.con) as f64;
+
+    let t = if stretch_length > length {
+        let con_idx = ((fps * timing.con as f64).round() as usize).min(timing.positions.len());
+        let len_idx = (fps * length) as usize;
+
+        timing.positions[con_idx..(con_idx+len_idx).min(timing.positions.len())].to_vec()
+    } else {
+        linspace(timing.con as f64, timing.end as f64, (fps * length) as usize)
+    };
+
+    t.into_iter().map(StretchFrame::at).collect()
+}
+
+/// Find the window of the sustained region with the least spectral change, at least `min_length` seconds long.
+/// The window spans half of the region, so loops keep some of the natural movement of the vowel.
+pub fn stable_region(sp: &[Vec<f64>], timing: &TimingData, min_length: f64) -> (f64, f64) {
+    let fps = timing.frame_rate();
+    let last = sp.len().saturating_sub(1);
+    let con_idx = ((timing.con as f64 * fps).round().max(0.0) as usize).min(last);
+    let end_idx = ((timing.end as f64 * fps).round().max(0.0) as usize).min(last);
+
+    if end_idx <= con_idx + 1 {
+        return (timing.con as f64, timing.end as f64);
+    }
+
+    // Log-spectral flux between consecutive frames
+    let flux = (con_idx + 1..=end_idx).map(|i| {
+        sp[i].iter().zip(sp[i - 1].iter())
+            .map(|(a, b)| (a.max(1e-16).ln() - b.max(1e-16).ln()).abs())
+            .sum::<f64>() / sp[i].len() as f64
+    }).collect::<Vec<f64>>();
+
+    let window = (flux.len() / 2).max((min_length * fps).ceil() as usize).min(flux.len());
+
+    let mut best = (f64::MAX, 0);
+    for s in 0..=flux.len() - window {
+        let cost = flux[s..s + window].iter().sum::<f64>();
+        if cost < best.0 {
+            best = (cost, s);
+        }
+    }
+
+    let start = (con_idx + best.1) as f64 / fps;
+    (start, start + window as f64 / fps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One second on a 5 ms grid, with a wobbling first half and a steady second half
+    fn sample() -> (Vec<Vec<f64>>, TimingData) {
+        let sp = (0..200).map(|i| {
+            let level = if i < 100 { 1.0 + 0.5 * (i as f64).sin() } else { 1.0 };
+            vec![level; 16]
+        }).collect::<Vec<Vec<f64>>>();
+
+        (sp, TimingData::calculate(200, 5.0, 100.0, 0.0, 50.0).unwrap())
+    }
+
+    #[test]
+    fn test_stable_region() {
+        let (sp, timing) = sample();
+        let (ls, le) = stable_region(&sp, &timing, 0.06);
+
+        assert!(ls >= 0.5 - 1e-9, "region starts at {}", ls);
+        assert!(le <= timing.end as f64 + 1e-9);
+        assert!(le - ls >= 0.06);
+    }
+
+    #[test]
+    fn test_modes_cover_requested_length() {
+        let (sp, timing) = sample();
+        let config = StretchConfig::default();
+
+        for mode in [StretchMode::Interpolate, StretchMode::Loop, StretchMode::PingPong, StretchMode::Random] {
+            let frames = stretch(mode, &sp, &timing, 3.0, &config, 0);
+
+            assert_eq!(frames.len(), 600, "{:?}", mode);
+            for f in frames {
+                assert!(f.t >= timing.con as f64 - 1e-9 && f.t <= timing.end as f64 + 1e-9, "{:?}: {:?}", mode, f);
+                assert!(f.t_alt >= timing.con as f64 - 1e-9 && f.t_alt <= timing.end as f64 + 1e-9, "{:?}: {:?}", mode, f);
+                assert!((0.0..=1.0).contains(&f.mix));
+            }
+        }
+    }
+
+    #[test]
+    fn test_ping_pong_is_continuous() {
+        let (sp, timing) = sample();
+        let frames = stretch(StretchMode::PingPong, &sp, &timing, 3.0, &StretchConfig::default(), 0);
+
+        for w in frames.windows(2) {
+            assert!((w[1].t - w[0].t).abs() <= 0.005 + 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_loop_crossfades_into_seam() {
+        let (sp, timing) = sample();
+        let frames = stretch(StretchMode::Loop, &sp, &timing, 3.0, &StretchConfig::default(), 0);
+
+        // Wherever playback jumps back, the previous frame was already fully faded to the jump target
+        for w in frames.windows(2) {
+            if w[1].t < w[0].t {
+                assert!(w[0].mix > 0.8);
+                assert!((w[1].t - w[0].t_alt).abs() <= 0.005 + 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn test_joins() {
+        let (sp, timing) = sample();
+        let config = StretchConfig::default();
+        let joins_for = |mode| joins(&stretch(mode, &sp, &timing, 3.0, &config, 0));
+
+        assert!(joins_for(StretchMode::Interpolate).is_empty());
+
+        // Ping-pong turns around at both ends of the stable region
+        let frames = stretch(StretchMode::PingPong, &sp, &timing, 3.0, &config, 0);
+        let (ls, le) = stable_region(&sp, &timing, 2.0 * config.crossfade / 1000.0);
+        assert!(joins(&frames).len() >= 2);
+        for k in joins(&frames) {
+            let t = frames[k - 1].t;
+            assert!((t - ls).abs() < 0.006 || (t - le).abs() < 0.006, "turn at {}", t);
+        }
+
+        // Every loop seam and random segment is marked once
+        for mode in [StretchMode::Loop, StretchMode::Random] {
+            let frames = stretch(mode, &sp, &timing, 3.0, &config, 0);
+            let starts = frames.windows(2).filter(|w| w[1].t_alt != w[1].t && w[0].t_alt == w[0].t).count();
+
+            assert!(starts > 0);
+            assert_eq!(joins(&frames).len(), starts, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn test_random_is_deterministic() {
+        let (sp, timing) = sample();
+        let config = StretchConfig::default();
+
+        assert_eq!(
+            stretch(StretchMode::Random, &sp, &timing, 3.0, &config, 7),
+            stretch(StretchMode::Random, &sp, &timing, 3.0, &config, 7)
+        );
+    }
+
+    #[test]
+    fn test_short_request_plays_through() {
+        let (sp, timing) = sample();
+        let frames = stretch(StretchMode::Loop, &sp, &timing, 0.2, &StretchConfig::default(), 0);
+
+        assert_eq!(frames, interpolate(&timing, 0.2));
+    }
+}
\ No newline at end of file
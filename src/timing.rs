@@ -1,35 +1,146 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum TimingError {
+    #[error("sample has {0} frames, at least 2 are needed")]
+    SampleTooShort(usize),
+    #[error("offset {offset} ms is past the end of the {length} ms sample")]
+    OffsetPastEnd { offset: f32, length: f32 },
+    #[error("cutoff leaves nothing after the offset (start {start} ms, end {end} ms)")]
+    EmptyRegion { start: f32, end: f32 },
+}
+
 pub struct TimingData {
     pub positions: Vec<f64>,
+    pub frame_period: f64,
     pub start: f32,
     pub end: f32,
     pub con: f32
 }
 
 impl TimingData {
-    pub fn calculate(f0_len: usize, offset: f32, cutoff: f32, consonant: f32) -> TimingData {
+    /// Calculate the sample timing from oto values in ms. Offsets and lengths that stick out of the sample
+    /// are clamped with a warning; entries that leave nothing to render are rejected.
+    pub fn calculate(f0_len: usize, frame_period: f64, offset: f32, cutoff: f32, consonant: f32) -> Result<TimingData, TimingError> {
         // Calculate timing
         log::info!("Calculating timing");
 
+        if f0_len < 2 {
+            return Err(TimingError::SampleTooShort(f0_len));
+        }
+
         let mut t_area = vec![];
         for i in 0..f0_len {
-            t_area.push(i as f64 * 0.005);
+            t_area.push(i as f64 * frame_period / 1000.0);
         }
-        
+        let length = *t_area.last().unwrap() as f32;
+
+        let offset = if offset < 0.0 {
+            log::warn!("Negative offset {} ms, using 0", offset);
+            0.0
+        } else {
+            offset
+        };
+
         let start: f32 = offset / 1000.0;
+        if start >= length {
+            return Err(TimingError::OffsetPastEnd { offset, length: length * 1000.0 });
+        }
+
         let end = cutoff / 1000.0;
         let end = if cutoff < 0.0 {
             start - end
         } else {
-            t_area.last().unwrap().clone() as f32 - end
+            length - end
+        };
+
+        let end = if end > length {
+            log::warn!("Cutoff ends {} ms past the end of the sample, clamping", (end - length) * 1000.0);
+            length
+        } else {
+            end
+        };
+
+        if end <= start {
+            return Err(TimingError::EmptyRegion { start: start * 1000.0, end: end * 1000.0 });
+        }
+
+        let consonant = if consonant < 0.0 {
+            log::warn!("Negative consonant {} ms, using 0", consonant);
+            0.0
+        } else {
+            consonant
         };
 
         let con = start + consonant / 1000.0;
+        let con = if con > end {
+            log::warn!("Consonant reaches {} ms past the cutoff, clamping", (con - end) * 1000.0);
+            end
+        } else {
+            con
+        };
 
-        TimingData {
+        Ok(TimingData {
             positions: t_area,
+            frame_period,
             start,
             end,
             con
+        })
+    }
+
+    /// Number of frames per second on the feature grid
+    pub fn frame_rate(&self) -> f64 {
+        1000.0 / self.frame_period
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn assert_timing(timing: &TimingData, start: f32, con: f32, end: f32) {
+        for (a, b) in [(timing.start, start), (timing.con, con), (timing.end, end)] {
+            assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+        }
+    }
+
+    #[test]
+    fn test_cutoff_forms() {
+        // One second sample
+        assert_timing(&TimingData::calculate(201, 5.0, 100.0, 200.0, 50.0).unwrap(), 0.1, 0.15, 0.8);
+        assert_timing(&TimingData::calculate(201, 5.0, 100.0, -300.0, 50.0).unwrap(), 0.1, 0.15, 0.4);
+    }
+
+    #[test]
+    fn test_clamping() {
+        assert_timing(&TimingData::calculate(201, 5.0, -20.0, -2000.0, 5000.0).unwrap(), 0.0, 1.0, 1.0);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(TimingData::calculate(1, 5.0, 0.0, 0.0, 0.0).err(), Some(TimingError::SampleTooShort(1)));
+        assert!(matches!(TimingData::calculate(201, 5.0, 1500.0, 0.0, 0.0), Err(TimingError::OffsetPastEnd { .. })));
+        assert!(matches!(TimingData::calculate(201, 5.0, 100.0, 950.0, 0.0), Err(TimingError::EmptyRegion { .. })));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_timing_is_ordered(
+            f0_len in 0usize..400,
+            offset in -500.0f32..3000.0,
+            cutoff in -3000.0f32..3000.0,
+            consonant in -500.0f32..3000.0,
+        ) {
+            if let Ok(timing) = TimingData::calculate(f0_len, 5.0, offset, cutoff, consonant) {
+                let length = *timing.positions.last().unwrap() as f32;
+
+                prop_assert!(0.0 <= timing.start);
+                prop_assert!(timing.start <= timing.con);
+                prop_assert!(timing.con <= timing.end);
+                prop_assert!(timing.end <= length);
+            }
         }
     }
 }
\ No newline at end of file
@@ -15,7 +15,12 @@ impl Div<Scalar> for Vector {
 }
 
 
+/// `n` evenly spaced values from `start` to `end`. A single value is `start`, and none is empty.
 pub fn linspace<T: Float>(start: T, end: T, n: usize) -> Vec<T> {
+    if n < 2 {
+        return vec![start; n];
+    }
+
     let dx = (end - start) / T::from(n - 1).unwrap();
     (0..n).map(|i| start + T::from(i).unwrap() * dx).collect()
 }
\ No newline at end of file
@@ -1,7 +1,8 @@
 use ndarray::prelude::*;
 
 use std::collections::HashMap;
-use anyhow::Result;
+use std::sync::LazyLock;
+use anyhow::{anyhow, Result};
 use regex::Regex;
 
 pub const F0_FLOOR: f64 = 71.0;
@@ -26,8 +27,9 @@ pub fn get_notes() -> HashMap<String, i32> {
     ])
 }
 
-pub fn get_fft_size() -> i32 {
-    2048
+/// FFT size used by CheapTrick for `fs`, so that a window covers three periods of F0_FLOOR
+pub fn fft_size_for(fs: i32) -> i32 {
+    2i32.pow(1 + (3.0 * fs as f64 / F0_FLOOR).log2().floor() as u32)
 }
 
 pub fn smoothstep(edge0: f64, edge1: f64, x: &Array1<f64>) -> Array1<f64> {
@@ -148,39 +150,55 @@ pub fn ftom(freq: f64) -> f64 {
     69.0 + 12.0 * (freq / 440.0).log2()
 }
 
-pub fn note_to_midi(note: &str) -> f64 {
-    // Regex to split note into note and octave
-    let re = Regex::new(r"([A-Ga-g]+)(\d+)").unwrap();
-    let caps = re.captures(note).unwrap();
+/// Note name, accidental and octave of a note name
+static NOTE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^([A-Ga-g])(#|b)?(-?\d+)$").unwrap());
 
-    let note = caps.get(1).unwrap().as_str();
-    let octave = caps.get(2).unwrap().as_str().parse::<i32>().unwrap();
-
-    let notes = get_notes();
-    let note = notes.get(note).unwrap();
-
-    (12 * (octave + 1) + *note) as f64
+/// MIDI note number of the note UTAU passes the resampler, failing if `note` isn't one
+pub fn note_to_midi(note: &str) -> Result<f64> {
+    parse_note(note).ok_or_else(|| anyhow!("{:?} is not a note name", note))
 }
 
-pub fn dump_test_data(sp: &Vec<Vec<f64>>, ap: &Vec<Vec<f64>>, f0: &Vec<f64>, t: &Vec<f64>, path: &str) {
-    let mut data = String::new();
-    let j = sp[0].len() / 2;
-
-    data.push_str("[Time]\t[F0]\t[Harmonic]\t[Aperiodic]\n");
+/// MIDI note number of a note name such as `C4`, `A#3` or `Eb5`, or `None` if `name` isn't one
+pub fn parse_note(name: &str) -> Option<f64> {
+    let caps = NOTE.captures(name.trim())?;
 
-    for i in 0..sp.len() {
-        data.push_str(&format!("{}\t{}\t{}\t{}\n", t[i], f0[i], sp[i][0], ap[i][0]));
-    }
+    let note = *get_notes().get(&caps[1].to_uppercase())?;
+    let accidental = match caps.get(2).map(|m| m.as_str()) {
+        Some("#") => 1,
+        Some(_) => -1,
+        None => 0,
+    };
+    let octave = caps[3].parse::<i32>().ok()?;
 
-    std::fs::create_dir_all("test").unwrap();
-    std::fs::write(path, data).unwrap();
+    Some((12 * (octave + 1) + note + accidental) as f64)
 }
 
-
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_fft_size_for() {
+        assert_eq!(fft_size_for(22050), 1024);
+        assert_eq!(fft_size_for(44100), 2048);
+        assert_eq!(fft_size_for(48000), 2048);
+        assert_eq!(fft_size_for(96000), 4096);
+    }
+
+    #[test]
+    fn test_parse_note() {
+        assert_eq!(parse_note("C4"), Some(60.0));
+        assert_eq!(parse_note("A#3"), Some(58.0));
+        assert_eq!(parse_note("Eb5"), Some(75.0));
+        assert_eq!(parse_note("c-1"), Some(0.0));
+        assert_eq!(parse_note("high"), None);
+        assert_eq!(parse_note("C4_soft"), None);
+
+        // What UTAU passes the resampler, sharps included
+        assert_eq!(note_to_midi("C#4").unwrap(), 61.0);
+        assert!(note_to_midi("100").is_err());
+    }
+
     #[test]
     fn test_pitch_string_to_cents() {
         let s = "B7CPCVCVCTCQCNCICDB+B5B0BvBrBnBlBk#14#BjBF/++Y8k615d4p4f4l4y5G5f596e7B7l8H8n9D9Z9q9092919y9t9n9f9Y9Q9I9C898584858/9L9b9v+G+f+4/Q/m/5AIATAY#2#AWAUARAOALAHAFACABAA";
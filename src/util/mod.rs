@@ -1,2 +1,4 @@
 pub mod math;
-pub mod misc;
\ No newline at end of file
+pub mod misc;
+#[cfg(test)]
+pub mod testing;
\ No newline at end of file
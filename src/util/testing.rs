@@ -0,0 +1,17 @@
+//! Fixtures shared by the tests
+
+use std::path::PathBuf;
+
+/// `name` in the scratch folder of the tests, with its parent folder created
+pub fn temp(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join("straycrab_tests").join(name);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    path
+}
+
+/// `len` samples of a tone at `freq` with its first five harmonics, which Harvest and D4C analyze reliably
+pub fn harmonic_tone(freq: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+    (0..len).map(|i| {
+        (1..=5).map(|h| (2.0 * std::f32::consts::PI * freq * h as f32 * i as f32 / sample_rate as f32).sin() / h as f32).sum::<f32>() * 0.3
+    }).collect()
+}